@@ -0,0 +1,125 @@
+//! Delegated access. The owner can allow other accounts (e.g. other devices or bots) to use
+//! some of the owner-only methods, optionally until some time.
+//!
+//! Ownership itself is transferred in two steps: the owner proposes a new owner, who must
+//! accept before the change takes effect. This avoids handing the contract to an account
+//! nobody controls.
+
+use crate::{
+    events::Event,
+    page_from_index,
+    types::{Delegate, DelegateWithAccount, Page, Permission},
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{env, json_types::U64, near_bindgen, require, AccountId};
+
+#[near_bindgen]
+impl MessengerContract {
+    /// Allow `account` to act on the owner's behalf with the given permissions. This replaces
+    /// any permissions previously granted to the account.
+    pub fn grant_permissions(
+        &mut self,
+        account: AccountId,
+        permissions: Vec<Permission>,
+        expires_at: Option<U64>,
+    ) {
+        self.require_owner_only();
+        require!(
+            account != self.owner,
+            "The owner already has every permission"
+        );
+        require!(
+            !permissions.is_empty(),
+            "Use revoke_permissions to remove all permissions"
+        );
+        let delegate = Delegate {
+            permissions,
+            expires_at,
+        };
+        self.delegates.insert(&account, &delegate);
+    }
+
+    /// Remove all permissions granted to `account`.
+    pub fn revoke_permissions(&mut self, account: AccountId) {
+        self.require_owner_only();
+        self.delegates.remove(&account);
+    }
+
+    /// Propose `new_owner` as the owner of the contract. The current owner stays the owner
+    /// until `new_owner` calls `accept_ownership`. A later proposal replaces this one.
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        let owner = self.require_owner_only();
+        require!(new_owner != owner, "This account is already the owner");
+        Event::ownership_proposed(&owner, &new_owner).emit();
+        self.proposed_owner = Some(new_owner);
+    }
+
+    /// Withdraw the pending proposal, so that the proposed owner can no longer accept it.
+    pub fn cancel_owner_proposal(&mut self) {
+        self.require_owner_only();
+        require!(
+            self.proposed_owner.take().is_some(),
+            "There is no pending proposal"
+        );
+    }
+
+    /// Called by the proposed owner to become the owner of the contract. The permissions
+    /// granted by the previous owner are revoked.
+    pub fn accept_ownership(&mut self) {
+        let predecessor_account = env::predecessor_account_id();
+        require!(
+            self.proposed_owner.as_ref() == Some(&predecessor_account),
+            "Only the proposed owner can accept the ownership!"
+        );
+        let previous_owner = std::mem::replace(&mut self.owner, predecessor_account);
+        self.proposed_owner = None;
+        self.delegates.clear();
+        Event::ownership_transferred(&previous_owner, &self.owner).emit();
+    }
+
+    pub fn view_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn view_proposed_owner(&self) -> Option<AccountId> {
+        self.proposed_owner.clone()
+    }
+
+    pub fn view_delegate(&self, account: AccountId) -> Option<Delegate> {
+        self.delegates.get(&account)
+    }
+
+    /// All accounts which have been granted permissions, including expired ones.
+    pub fn view_delegates(
+        &self,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<DelegateWithAccount, u64> {
+        let delegates = self.delegates.iter();
+        let Page { items, next } =
+            page_from_index(delegates, self.delegates.len(), from_index, max_size);
+        let items = items
+            .into_iter()
+            .map(|(account, delegate)| DelegateWithAccount { account, delegate })
+            .collect();
+        Page { items, next }
+    }
+}
+
+impl MessengerContract {
+    /// Require the caller is the owner, or an account the owner granted the permission to.
+    /// Returns the caller, who pays for anything the call requires a deposit for.
+    pub(crate) fn require_permission(&self, permission: Permission) -> AccountId {
+        let predecessor_account = env::predecessor_account_id();
+        if self.owner == predecessor_account {
+            return predecessor_account;
+        }
+        let allowed = self
+            .delegates
+            .get(&predecessor_account)
+            .map(|delegate| delegate.allows(permission, env::block_timestamp()))
+            .unwrap_or(false);
+        require!(allowed, "You do not have permission to use this method!");
+        predecessor_account
+    }
+}
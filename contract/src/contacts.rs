@@ -0,0 +1,86 @@
+//! Metadata the owner keeps about each contact (nickname, notes, muted and archived flags),
+//! so that it follows them across devices. An account's metadata is created when it becomes
+//! our contact and dropped when it stops being one. Contacts made by the first release have
+//! default metadata until it is set, and are listed once the owner backfills them.
+
+use crate::{
+    page_from_index,
+    types::{AccountStatus, ContactMetadata, ContactWithMetadata, Page, Permission},
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{near_bindgen, require, AccountId};
+
+#[near_bindgen]
+impl MessengerContract {
+    /// Replace the metadata kept about one of our contacts.
+    pub fn set_contact_metadata(&mut self, account: AccountId, metadata: ContactMetadata) {
+        self.require_permission(Permission::ManageContacts);
+
+        require!(
+            matches!(self.accounts.get(&account), Some(AccountStatus::Contact)),
+            "This account is not a contact"
+        );
+        self.contacts.insert(&account, &metadata);
+    }
+
+    pub fn view_contact_metadata(&self, account: AccountId) -> Option<ContactMetadata> {
+        self.contacts.get(&account).or_else(|| {
+            matches!(self.accounts.get(&account), Some(AccountStatus::Contact))
+                .then(ContactMetadata::default)
+        })
+    }
+
+    /// List those of `accounts` which are our contacts but have no metadata yet, because
+    /// they became contacts before the metadata was added to the contract. The `accounts`
+    /// map can not be gone through, so the owner names them. Returns the number of contacts
+    /// added to `view_contacts`.
+    pub fn backfill_contacts(&mut self, accounts: Vec<AccountId>) -> u32 {
+        self.require_owner_only();
+
+        let mut added = 0;
+        for account in accounts {
+            let is_contact = matches!(self.accounts.get(&account), Some(AccountStatus::Contact));
+            if is_contact && self.contacts.get(&account).is_none() {
+                self.contacts.insert(&account, &ContactMetadata::default());
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Our contacts, along with their metadata. Contacts made before the metadata was
+    /// added to the contract are listed once it is set, or once `backfill_contacts` adds
+    /// them.
+    pub fn view_contacts(
+        &self,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<ContactWithMetadata, u64> {
+        let contacts = self.contacts.iter();
+        let Page { items, next } =
+            page_from_index(contacts, self.contacts.len(), from_index, max_size);
+        let items = items
+            .into_iter()
+            .map(|(account, metadata)| ContactWithMetadata { account, metadata })
+            .collect();
+        Page { items, next }
+    }
+}
+
+impl MessengerContract {
+    /// Mark `account` as our contact, keeping any metadata it already has.
+    /// Returns the previous status of the account.
+    pub(crate) fn insert_contact(&mut self, account: &AccountId) -> Option<AccountStatus> {
+        if self.contacts.get(account).is_none() {
+            self.contacts.insert(account, &ContactMetadata::default());
+        }
+        self.accounts.insert(account, &AccountStatus::Contact)
+    }
+
+    pub(crate) fn is_muted(&self, account: &AccountId) -> bool {
+        self.contacts
+            .get(account)
+            .map(|metadata| metadata.muted)
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,102 @@
+//! Helpers to encrypt and decrypt message content for an account's published encryption
+//! key (see `view_encryption_key`). They are meant for off-chain tools; the contract itself
+//! only ever handles the encrypted payloads. Enabled with the `encryption` feature.
+//!
+//! Content is encrypted with `crypto_box` (X25519, XSalsa20-Poly1305) using a fresh sender
+//! key for every message. The public half of that key is prepended to the ciphertext, so the
+//! recipient only needs their own secret key to decrypt.
+
+use crate::types::{EncryptedPayload, EncryptionKey};
+use crypto_box::{
+    aead::{Aead, AeadCore, OsRng},
+    Nonce, SalsaBox, KEY_SIZE,
+};
+use std::fmt;
+
+pub use crypto_box::{PublicKey, SecretKey};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionError {
+    /// The public key is not a valid X25519 key.
+    InvalidPublicKey,
+    /// The payload is not the right shape to have been produced by `encrypt`.
+    MalformedPayload,
+    /// The content could not be encrypted, or decrypted with the given key.
+    Failed,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPublicKey => write!(f, "invalid encryption public key"),
+            Self::MalformedPayload => write!(f, "malformed encrypted payload"),
+            Self::Failed => write!(f, "encryption or decryption failed"),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+/// Generate a new key pair. The public key can be published with `set_encryption_key`.
+pub fn generate_secret_key() -> SecretKey {
+    SecretKey::generate(&mut OsRng)
+}
+
+/// Encrypt `plaintext` so that only the owner of `recipient_key` can read it.
+pub fn encrypt(
+    recipient_key: &EncryptionKey,
+    plaintext: &[u8],
+) -> Result<EncryptedPayload, EncryptionError> {
+    let public_key = PublicKey::from_slice(&recipient_key.public_key.0)
+        .map_err(|_e| EncryptionError::InvalidPublicKey)?;
+    let sender_key = generate_secret_key();
+    let nonce = SalsaBox::generate_nonce(&mut OsRng);
+    let encrypted = SalsaBox::new(&public_key, &sender_key)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_e| EncryptionError::Failed)?;
+
+    let mut ciphertext = sender_key.public_key().to_bytes().to_vec();
+    ciphertext.extend(encrypted);
+    Ok(EncryptedPayload {
+        ciphertext: ciphertext.into(),
+        nonce: nonce.to_vec().into(),
+        key_id: recipient_key.id,
+    })
+}
+
+/// Decrypt a payload with the secret key matching `payload.key_id`.
+pub fn decrypt(
+    secret_key: &SecretKey,
+    payload: &EncryptedPayload,
+) -> Result<Vec<u8>, EncryptionError> {
+    if payload.ciphertext.0.len() < KEY_SIZE || payload.nonce.0.len() != Nonce::default().len() {
+        return Err(EncryptionError::MalformedPayload);
+    }
+    let (sender_key, encrypted) = payload.ciphertext.0.split_at(KEY_SIZE);
+    let sender_key =
+        PublicKey::from_slice(sender_key).map_err(|_e| EncryptionError::MalformedPayload)?;
+    SalsaBox::new(&sender_key, secret_key)
+        .decrypt(Nonce::from_slice(&payload.nonce.0), encrypted)
+        .map_err(|_e| EncryptionError::Failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_round_trip() {
+        let secret_key = generate_secret_key();
+        let recipient_key = EncryptionKey {
+            id: 3,
+            public_key: secret_key.public_key().to_bytes().to_vec().into(),
+        };
+
+        let payload = encrypt(&recipient_key, b"Hello, Bob!").unwrap();
+        assert_eq!(payload.key_id, 3);
+        assert_eq!(decrypt(&secret_key, &payload).unwrap(), b"Hello, Bob!");
+
+        let other_key = generate_secret_key();
+        assert_eq!(decrypt(&other_key, &payload), Err(EncryptionError::Failed));
+    }
+}
@@ -22,7 +22,7 @@ impl<'a> Event<'a> {
     /// This should be a NEP number (after making a NEP proposal of course), but this is just an
     /// example, not a real Near standard.
     pub const STANDARD: &str = "NearMessenger";
-    pub const VERSION: &str = "1.0.0";
+    pub const VERSION: &str = "1.1.0";
 
     /// Create an event for having sent a pending contact request.
     pub fn pending_contact_request(sender: &'a AccountId, receiver: &'a AccountId) -> Self {
@@ -42,6 +42,24 @@ impl<'a> Event<'a> {
         Self::with_kind(kind)
     }
 
+    /// Create an event for a contact request having been declined by its receiver.
+    pub fn contact_request_declined(sender: &'a AccountId, receiver: &'a AccountId) -> Self {
+        let kind = EventKind::ContactRequestDeclined(PendingContactRequest {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for a contact request having been cancelled by its sender.
+    pub fn contact_request_cancelled(sender: &'a AccountId, receiver: &'a AccountId) -> Self {
+        let kind = EventKind::ContactRequestCancelled(PendingContactRequest {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
     /// Create an event for having added a new contact.
     /// `this` refers to the account that has added the new contact.
     pub fn new_contact(this: &'a AccountId, contact: &'a AccountId) -> Self {
@@ -52,11 +70,28 @@ impl<'a> Event<'a> {
         Self::with_kind(kind)
     }
 
+    /// Create an event for having removed a contact.
+    /// `this` refers to the account that no longer has `contact` as a contact.
+    pub fn contact_removed(this: &'a AccountId, contact: &'a AccountId) -> Self {
+        let kind = EventKind::ContactRemoved(NewContact {
+            this: this.borrowed(),
+            contact: contact.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
     /// Create an event for having sent a message.
-    pub fn message_sent(sender: &'a AccountId, receiver: &'a AccountId) -> Self {
+    pub fn message_sent(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+        message_kind: types::MessageKind,
+    ) -> Self {
         let kind = EventKind::MessageSent(MessageSent {
             sender: sender.borrowed(),
             receiver: receiver.borrowed(),
+            message_id: Some(id.borrowed()),
+            kind: Some(message_kind),
         });
         Self::with_kind(kind)
     }
@@ -66,11 +101,238 @@ impl<'a> Event<'a> {
         sender: &'a AccountId,
         receiver: &'a AccountId,
         id: &'a types::MessageId,
+        message_kind: types::MessageKind,
     ) -> Self {
         let kind = EventKind::MessageReceived(MessageReceived {
             sender: sender.borrowed(),
             receiver: receiver.borrowed(),
             message_id: id.borrowed(),
+            kind: Some(message_kind),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having received a message request from an account which is not
+    /// our contact.
+    pub fn message_request_received(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+        message_kind: types::MessageKind,
+    ) -> Self {
+        let kind = EventKind::MessageRequestReceived(MessageReceived {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            kind: Some(message_kind),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having received an edit of a message.
+    /// `message_kind` is the kind of the new content.
+    pub fn message_edited(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+        message_kind: types::MessageKind,
+    ) -> Self {
+        let kind = EventKind::MessageEdited(MessageReceived {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            kind: Some(message_kind),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for a message we received having been retracted by its sender.
+    pub fn message_retracted(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+    ) -> Self {
+        let kind = EventKind::MessageRetracted(MessageReceived {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            kind: None,
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for a message we received having been removed because it expired.
+    pub fn message_expired(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+    ) -> Self {
+        let kind = EventKind::MessageExpired(MessageReceived {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            kind: None,
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for a message we sent having been read by its receiver.
+    pub fn message_read(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+    ) -> Self {
+        let kind = EventKind::MessageRead(MessageReceived {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            kind: None,
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having received a reaction to a message.
+    /// `sender` refers to the account that reacted.
+    pub fn message_reaction(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+        reaction: &'a str,
+    ) -> Self {
+        let kind = EventKind::MessageReaction(MessageReaction {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            reaction: Cow::Borrowed(reaction),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having learned the delivery status of a message we sent.
+    pub fn message_delivery(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        id: &'a types::MessageId,
+        status: &'a types::DeliveryStatus,
+    ) -> Self {
+        let kind = EventKind::MessageDelivery(MessageDelivery {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            message_id: id.borrowed(),
+            status: status.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having created a group.
+    pub fn group_created(creator: &'a AccountId, group_id: &'a types::GroupId) -> Self {
+        let kind = EventKind::GroupCreated(GroupCreated {
+            creator: creator.borrowed(),
+            group_id: group_id.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for `member` having been added to a group.
+    /// `this` refers to the account that learned of the change.
+    pub fn group_member_added(
+        this: &'a AccountId,
+        group_id: &'a types::GroupId,
+        member: &'a AccountId,
+    ) -> Self {
+        let kind = EventKind::GroupMemberAdded(GroupMember {
+            this: this.borrowed(),
+            group_id: group_id.borrowed(),
+            member: member.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for `member` having been removed from a group.
+    /// `this` refers to the account that learned of the change.
+    pub fn group_member_removed(
+        this: &'a AccountId,
+        group_id: &'a types::GroupId,
+        member: &'a AccountId,
+    ) -> Self {
+        let kind = EventKind::GroupMemberRemoved(GroupMember {
+            this: this.borrowed(),
+            group_id: group_id.borrowed(),
+            member: member.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having sent a message to a group.
+    pub fn group_message_sent(
+        sender: &'a AccountId,
+        group_id: &'a types::GroupId,
+        id: &'a types::MessageId,
+        message_kind: types::MessageKind,
+    ) -> Self {
+        let kind = EventKind::GroupMessageSent(GroupMessageSent {
+            sender: sender.borrowed(),
+            group_id: group_id.borrowed(),
+            message_id: id.borrowed(),
+            kind: Some(message_kind),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having received a message in a group.
+    pub fn group_message_received(
+        sender: &'a AccountId,
+        receiver: &'a AccountId,
+        group_id: &'a types::GroupId,
+        id: &'a types::MessageId,
+        message_kind: types::MessageKind,
+        muted: bool,
+    ) -> Self {
+        let kind = EventKind::GroupMessageReceived(GroupMessageReceived {
+            sender: sender.borrowed(),
+            receiver: receiver.borrowed(),
+            group_id: group_id.borrowed(),
+            message_id: id.borrowed(),
+            kind: Some(message_kind),
+            muted,
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having blocked an account.
+    /// `this` refers to the account that blocked `account`.
+    pub fn account_blocked(this: &'a AccountId, account: &'a AccountId) -> Self {
+        let kind = EventKind::AccountBlocked(BlockedAccount {
+            this: this.borrowed(),
+            account: account.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having unblocked an account.
+    /// `this` refers to the account that unblocked `account`.
+    pub fn account_unblocked(this: &'a AccountId, account: &'a AccountId) -> Self {
+        let kind = EventKind::AccountUnblocked(BlockedAccount {
+            this: this.borrowed(),
+            account: account.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for having proposed a new owner of the contract.
+    pub fn ownership_proposed(owner: &'a AccountId, proposed_owner: &'a AccountId) -> Self {
+        let kind = EventKind::OwnershipProposed(Ownership {
+            previous_owner: owner.borrowed(),
+            new_owner: proposed_owner.borrowed(),
+        });
+        Self::with_kind(kind)
+    }
+
+    /// Create an event for the proposed owner having accepted the ownership of the contract.
+    pub fn ownership_transferred(previous_owner: &'a AccountId, new_owner: &'a AccountId) -> Self {
+        let kind = EventKind::OwnershipTransferred(Ownership {
+            previous_owner: previous_owner.borrowed(),
+            new_owner: new_owner.borrowed(),
         });
         Self::with_kind(kind)
     }
@@ -96,6 +358,20 @@ impl<'a> Event<'a> {
         }
     }
 
+    pub fn as_contact_request_declined(&self) -> Option<&PendingContactRequest<'a>> {
+        match &self.event_kind {
+            EventKind::ContactRequestDeclined(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_contact_request_cancelled(&self) -> Option<&PendingContactRequest<'a>> {
+        match &self.event_kind {
+            EventKind::ContactRequestCancelled(x) => Some(x),
+            _ => None,
+        }
+    }
+
     pub fn as_new_contact(&self) -> Option<&NewContact<'a>> {
         match &self.event_kind {
             EventKind::NewContact(x) => Some(x),
@@ -103,6 +379,13 @@ impl<'a> Event<'a> {
         }
     }
 
+    pub fn as_contact_removed(&self) -> Option<&NewContact<'a>> {
+        match &self.event_kind {
+            EventKind::ContactRemoved(x) => Some(x),
+            _ => None,
+        }
+    }
+
     pub fn as_message_sent(&self) -> Option<&MessageSent<'a>> {
         match &self.event_kind {
             EventKind::MessageSent(x) => Some(x),
@@ -117,6 +400,100 @@ impl<'a> Event<'a> {
         }
     }
 
+    pub fn as_message_request_received(&self) -> Option<&MessageReceived<'a>> {
+        match &self.event_kind {
+            EventKind::MessageRequestReceived(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_message_edited(&self) -> Option<&MessageReceived<'a>> {
+        match &self.event_kind {
+            EventKind::MessageEdited(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_message_retracted(&self) -> Option<&MessageReceived<'a>> {
+        match &self.event_kind {
+            EventKind::MessageRetracted(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_message_expired(&self) -> Option<&MessageReceived<'a>> {
+        match &self.event_kind {
+            EventKind::MessageExpired(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_message_read(&self) -> Option<&MessageReceived<'a>> {
+        match &self.event_kind {
+            EventKind::MessageRead(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_message_reaction(&self) -> Option<&MessageReaction<'a>> {
+        match &self.event_kind {
+            EventKind::MessageReaction(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_message_delivery(&self) -> Option<&MessageDelivery<'a>> {
+        match &self.event_kind {
+            EventKind::MessageDelivery(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_group_created(&self) -> Option<&GroupCreated<'a>> {
+        match &self.event_kind {
+            EventKind::GroupCreated(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_group_member(&self) -> Option<&GroupMember<'a>> {
+        match &self.event_kind {
+            EventKind::GroupMemberAdded(x) => Some(x),
+            EventKind::GroupMemberRemoved(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_group_message_sent(&self) -> Option<&GroupMessageSent<'a>> {
+        match &self.event_kind {
+            EventKind::GroupMessageSent(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_group_message_received(&self) -> Option<&GroupMessageReceived<'a>> {
+        match &self.event_kind {
+            EventKind::GroupMessageReceived(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_blocked_account(&self) -> Option<&BlockedAccount<'a>> {
+        match &self.event_kind {
+            EventKind::AccountBlocked(x) => Some(x),
+            EventKind::AccountUnblocked(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_ownership(&self) -> Option<&Ownership<'a>> {
+        match &self.event_kind {
+            EventKind::OwnershipProposed(x) => Some(x),
+            EventKind::OwnershipTransferred(x) => Some(x),
+            _ => None,
+        }
+    }
+
     fn with_kind(event_kind: EventKind<'a>) -> Self {
         Self {
             standard: Cow::Borrowed(Self::STANDARD),
@@ -136,6 +513,25 @@ pub enum EventKind<'a> {
     NewContact(NewContact<'a>),
     MessageSent(MessageSent<'a>),
     MessageReceived(MessageReceived<'a>),
+    MessageDelivery(MessageDelivery<'a>),
+    MessageEdited(MessageReceived<'a>),
+    MessageRetracted(MessageReceived<'a>),
+    MessageRead(MessageReceived<'a>),
+    MessageReaction(MessageReaction<'a>),
+    AccountBlocked(BlockedAccount<'a>),
+    AccountUnblocked(BlockedAccount<'a>),
+    GroupCreated(GroupCreated<'a>),
+    GroupMemberAdded(GroupMember<'a>),
+    GroupMemberRemoved(GroupMember<'a>),
+    GroupMessageSent(GroupMessageSent<'a>),
+    GroupMessageReceived(GroupMessageReceived<'a>),
+    OwnershipProposed(Ownership<'a>),
+    OwnershipTransferred(Ownership<'a>),
+    ContactRemoved(NewContact<'a>),
+    ContactRequestDeclined(PendingContactRequest<'a>),
+    ContactRequestCancelled(PendingContactRequest<'a>),
+    MessageRequestReceived(MessageReceived<'a>),
+    MessageExpired(MessageReceived<'a>),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -157,6 +553,12 @@ pub struct NewContact<'a> {
 pub struct MessageSent<'a> {
     pub sender: Cow<'a, AccountId>,
     pub receiver: Cow<'a, AccountId>,
+    /// Not logged by the first release (version 1.0.0 of the events).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<Cow<'a, types::MessageId>>,
+    /// The kind of content of the message, so that it can be told apart without viewing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<types::MessageKind>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -165,6 +567,82 @@ pub struct MessageReceived<'a> {
     pub sender: Cow<'a, AccountId>,
     pub receiver: Cow<'a, AccountId>,
     pub message_id: Cow<'a, types::MessageId>,
+    /// The kind of content of the message, so that it can be told apart without viewing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<types::MessageKind>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessageReaction<'a> {
+    pub sender: Cow<'a, AccountId>,
+    pub receiver: Cow<'a, AccountId>,
+    pub message_id: Cow<'a, types::MessageId>,
+    pub reaction: Cow<'a, str>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessageDelivery<'a> {
+    pub sender: Cow<'a, AccountId>,
+    pub receiver: Cow<'a, AccountId>,
+    pub message_id: Cow<'a, types::MessageId>,
+    pub status: Cow<'a, types::DeliveryStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BlockedAccount<'a> {
+    pub this: Cow<'a, AccountId>,
+    pub account: Cow<'a, AccountId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupCreated<'a> {
+    pub creator: Cow<'a, AccountId>,
+    pub group_id: Cow<'a, types::GroupId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupMember<'a> {
+    pub this: Cow<'a, AccountId>,
+    pub group_id: Cow<'a, types::GroupId>,
+    pub member: Cow<'a, AccountId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupMessageSent<'a> {
+    pub sender: Cow<'a, AccountId>,
+    pub group_id: Cow<'a, types::GroupId>,
+    pub message_id: Cow<'a, types::MessageId>,
+    /// The kind of content of the message, so that it can be told apart without viewing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<types::MessageKind>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupMessageReceived<'a> {
+    pub sender: Cow<'a, AccountId>,
+    pub receiver: Cow<'a, AccountId>,
+    pub group_id: Cow<'a, types::GroupId>,
+    pub message_id: Cow<'a, types::MessageId>,
+    /// The kind of content of the message, so that it can be told apart without viewing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<types::MessageKind>,
+    /// The receiver muted the sender, so there is no need to notify them of the message.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub muted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Ownership<'a> {
+    pub previous_owner: Cow<'a, AccountId>,
+    pub new_owner: Cow<'a, AccountId>,
 }
 
 // Helper trait to enabled the `.borrowed` syntax above
@@ -199,4 +677,14 @@ mod tests {
             ),
         );
     }
+
+    #[test]
+    fn test_parse_first_release_message_sent() {
+        let log = r#"{"standard":"NearMessenger","version":"1.0.0","event":"message_sent","data":{"sender":"alice.near","receiver":"bob.near"}}"#;
+        let event: Event = serde_json::from_str(log).unwrap();
+        let event_details = event.as_message_sent().unwrap();
+        assert_eq!(event_details.receiver.as_str(), "bob.near");
+        assert_eq!(event_details.message_id, None);
+        assert_eq!(event_details.kind, None);
+    }
 }
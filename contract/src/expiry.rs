@@ -0,0 +1,125 @@
+//! Ephemeral messages. A message sent with a time-to-live expires that long after it was
+//! sent, on both sides of the conversation: the sender gives the recipient the time it
+//! expires at. Expired messages are hidden from every view straight away, but stay in storage
+//! until someone calls `sweep_expired`, which removes them and refunds the storage they used
+//! to whoever paid for it, in their storage balance with the sender. The `expiry_queue` orders messages by the time they expire, so
+//! that sweeping only goes through expired ones.
+
+use crate::{
+    events::Event,
+    storage::refund_to,
+    types::{MessageId, Tombstone},
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{env, json_types::U64, near_bindgen, AccountId, Balance};
+
+#[near_bindgen]
+impl MessengerContract {
+    /// View when a message we sent or received expires, if it was sent with a time-to-live.
+    pub fn view_message_expiry(&self, message_id: MessageId) -> Option<U64> {
+        self.message_expiries.get(&message_id).map(U64)
+    }
+
+    /// Remove up to `max` expired messages. Like a retracted message, each one leaves a
+    /// tombstone behind so that the rest of its thread can still be viewed. The storage
+    /// freed by a received message is refunded to its sender, as for a retracted message.
+    /// Anyone can call this method. Returns the number of messages removed.
+    pub fn sweep_expired(&mut self, max: usize) -> u32 {
+        let now = env::block_timestamp();
+        let expired: Vec<MessageId> = self
+            .expiry_queue
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .flat_map(|(_, ids)| ids)
+            .take(max)
+            .collect();
+        for id in &expired {
+            if !self.remove_expired_message(id, now) {
+                self.remove_expired_sent_message(id, now);
+            }
+        }
+        expired.len() as u32
+    }
+}
+
+impl MessengerContract {
+    /// Record when a message expires, if it was sent with a time-to-live, and who paid for a
+    /// received message on behalf of its sender.
+    pub(crate) fn set_message_expiry(
+        &mut self,
+        message_id: &MessageId,
+        expires_at: Option<U64>,
+        payer: Option<&AccountId>,
+    ) {
+        if let Some(U64(expires_at)) = expires_at {
+            self.message_expiries.insert(message_id, &expires_at);
+            if let Some(payer) = payer {
+                self.expiry_payers.insert(message_id, payer);
+            }
+            let mut ids = self.expiry_queue.get(&expires_at).unwrap_or_default();
+            ids.push(*message_id);
+            self.expiry_queue.insert(&expires_at, &ids);
+        }
+    }
+
+    pub(crate) fn remove_message_expiry(&mut self, message_id: &MessageId) {
+        let expires_at = match self.message_expiries.remove(message_id) {
+            Some(expires_at) => expires_at,
+            None => return,
+        };
+        self.expiry_payers.remove(message_id);
+        let mut ids = self.expiry_queue.get(&expires_at).unwrap_or_default();
+        ids.retain(|id| id != message_id);
+        if ids.is_empty() {
+            self.expiry_queue.remove(&expires_at);
+        } else {
+            self.expiry_queue.insert(&expires_at, &ids);
+        }
+    }
+
+    pub(crate) fn is_expired(&self, message_id: &MessageId) -> bool {
+        self.message_expiries
+            .get(message_id)
+            .map(|expires_at| expires_at <= env::block_timestamp())
+            .unwrap_or(false)
+    }
+
+    /// Replace an expired message we received with a tombstone. Returns false if there is
+    /// no such message. Only the storage the sender paid for is refunded, to the storage
+    /// balance of the account which paid on its behalf if there is one.
+    fn remove_expired_message(&mut self, id: &MessageId, now: u64) -> bool {
+        let message = match self.received_message(id) {
+            Some(message) => message,
+            None => return false,
+        };
+        let sender = message.sender.clone();
+        let payer = self.expiry_payers.get(id);
+        let freed = self.replace_with_tombstone(id, message, now);
+
+        let receiver = env::current_account_id();
+        Event::message_expired(&sender, &receiver, id).emit();
+
+        let refund = (freed as Balance) * env::STORAGE_PRICE_PER_BYTE;
+        refund_to(sender, refund, payer.as_ref());
+        true
+    }
+
+    /// Replace an expired message we sent with a tombstone.
+    fn remove_expired_sent_message(&mut self, id: &MessageId, now: u64) {
+        let sent_message = match self.sent_messages.remove(id) {
+            Some(sent_message) => sent_message,
+            None => {
+                // Nothing else refers to the expiry of an unknown message.
+                self.remove_message_expiry(id);
+                return;
+            }
+        };
+        self.remove_message_attachments(id);
+        let tombstone = Tombstone {
+            parent_id: sent_message.parent_id,
+            timestamp: sent_message.timestamp,
+            removed_at: U64(now),
+        };
+        self.sent_tombstones.insert(id, &tombstone);
+    }
+}
@@ -0,0 +1,461 @@
+//! Group conversations. A group is managed by the contract that created it, which sends
+//! the current state of the group to every member whenever it changes. Messages are
+//! sent directly from the poster's contract to every other member's contract.
+
+use crate::{
+    compute_required_message_deposit,
+    events::Event,
+    page_from_index,
+    storage::refund_deposit,
+    types::{
+        group_message_id, AccountStatus, DeliveryStatus, Group, GroupId, GroupUpdateResponse,
+        GroupWithId, Message, MessageBody, MessageId, MessageKind, MessageResponse, MessageWithId,
+        Page, Permission,
+    },
+    MessengerContract, MessengerContractExt, DEFAULT_THREAD_SIZE, MESSAGE_STORAGE_OVERHEAD,
+};
+use near_sdk::{
+    borsh::BorshSerialize,
+    env,
+    json_types::{U128, U64},
+    near_bindgen, require, AccountId, Balance, PromiseError,
+};
+use std::collections::HashMap;
+
+#[near_bindgen]
+impl MessengerContract {
+    pub fn view_group(&self, group_id: GroupId) -> Option<Group> {
+        self.groups.get(&group_id)
+    }
+
+    pub fn view_groups(
+        &self,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<GroupWithId, u64> {
+        let groups = self.groups.iter();
+        let Page { items, next } = page_from_index(groups, self.groups.len(), from_index, max_size);
+        let items = items
+            .into_iter()
+            .map(|(id, group)| GroupWithId { id, group })
+            .collect();
+        Page { items, next }
+    }
+
+    /// Shows the history of messages in the given group, including the ones we sent.
+    /// Only messages older than `before` are shown, if it is given. The page is empty if
+    /// `before` is not a group message.
+    pub fn view_group_thread(
+        &self,
+        group_id: GroupId,
+        max_size: Option<usize>,
+        before: Option<MessageId>,
+    ) -> Page<MessageWithId, MessageId> {
+        let max_size = max_size.unwrap_or(DEFAULT_THREAD_SIZE);
+        let mut result: Vec<MessageWithId> = Vec::with_capacity(max_size);
+        let mut next_message = match before {
+            Some(id) => match self.group_messages.get(&id) {
+                Some(message) => message.parent_id,
+                None => return Page::default(),
+            },
+            None => self.last_group_message.get(&group_id),
+        };
+        while result.len() < max_size {
+            let Some(id) = next_message.take() else {
+                break;
+            };
+            let message = self.get_group_message(&id);
+            next_message = message.parent_id;
+            result.push(MessageWithId { id, message });
+        }
+        let next = next_message.and(result.last().map(|m| m.id));
+        result.reverse();
+        Page {
+            items: result,
+            next,
+        }
+    }
+
+    /// Create a group with some of our contacts. The attached deposit (or the caller's storage
+    /// balance) pays for storing the group in the members' contracts.
+    #[payable]
+    pub fn create_group(&mut self, name: String, members: Vec<AccountId>) -> GroupId {
+        let caller = self.require_permission(Permission::ManageContacts);
+
+        let this = env::current_account_id();
+        let mut group = Group {
+            creator: this.clone(),
+            name,
+            members: vec![this.clone()],
+            created_at: U64(env::block_timestamp()),
+        };
+        for member in members {
+            if group.is_member(&member) {
+                continue;
+            }
+            self.require_contact(&member);
+            group.members.push(member);
+        }
+
+        let group_id = group.id();
+        require!(self.groups.get(&group_id).is_none(), "Group already exists");
+        self.groups.insert(&group_id, &group);
+        Event::group_created(&this, &group_id).emit();
+        for member in group.members.iter().filter(|m| *m != &this) {
+            Event::group_member_added(&this, &group_id, member).emit();
+        }
+
+        let recipients: Vec<AccountId> = group
+            .members
+            .iter()
+            .filter(|m| *m != &this)
+            .cloned()
+            .collect();
+        self.send_group_update(&caller, group_id, &group, recipients);
+
+        group_id
+    }
+
+    /// Add one of our contacts to a group we created.
+    #[payable]
+    pub fn invite_group_member(&mut self, group_id: GroupId, account: AccountId) {
+        let caller = self.require_permission(Permission::ManageContacts);
+
+        let mut group = self.get_own_group(&group_id);
+        require!(!group.is_member(&account), "Account is already a member");
+        self.require_contact(&account);
+        group.members.push(account.clone());
+        self.groups.insert(&group_id, &group);
+
+        let this = env::current_account_id();
+        Event::group_member_added(&this, &group_id, &account).emit();
+
+        let recipients: Vec<AccountId> = group
+            .members
+            .iter()
+            .filter(|m| *m != &this)
+            .cloned()
+            .collect();
+        self.send_group_update(&caller, group_id, &group, recipients);
+    }
+
+    /// Remove a member from a group we created. The removed member is told about the change
+    /// too, but keeps the history of the group.
+    #[payable]
+    pub fn remove_group_member(&mut self, group_id: GroupId, account: AccountId) {
+        let caller = self.require_permission(Permission::ManageContacts);
+
+        let mut group = self.get_own_group(&group_id);
+        let this = env::current_account_id();
+        require!(
+            account != this,
+            "The creator can not be removed from the group"
+        );
+        require!(group.is_member(&account), "Account is not a member");
+        group.members.retain(|m| m != &account);
+        self.groups.insert(&group_id, &group);
+
+        Event::group_member_removed(&this, &group_id, &account).emit();
+
+        let recipients: Vec<AccountId> = group
+            .members
+            .iter()
+            .filter(|m| *m != &this)
+            .chain(std::iter::once(&account))
+            .cloned()
+            .collect();
+        self.send_group_update(&caller, group_id, &group, recipients);
+    }
+
+    /// Called by the creator of a group when we are added to it, or its membership changes.
+    #[payable]
+    pub fn ext_update_group(
+        &mut self,
+        group_id: GroupId,
+        group: Group,
+        payer: Option<AccountId>,
+    ) -> GroupUpdateResponse {
+        let creator = env::predecessor_account_id();
+        if !self.can_pay(&creator, compute_required_group_deposit(&group)) {
+            refund_deposit(payer.as_ref());
+            return GroupUpdateResponse::InsufficientDeposit;
+        }
+
+        if group.creator != creator {
+            refund_deposit(payer.as_ref());
+            return GroupUpdateResponse::NotCreator;
+        }
+        let previous_group = self.groups.get(&group_id);
+        if let Some(previous_group) = &previous_group {
+            if previous_group.creator != creator {
+                refund_deposit(payer.as_ref());
+                return GroupUpdateResponse::NotCreator;
+            }
+        }
+        if group.id() != group_id {
+            refund_deposit(payer.as_ref());
+            return GroupUpdateResponse::InvalidId;
+        }
+        match self
+            .accounts
+            .get(&creator)
+            .unwrap_or(AccountStatus::Unknown)
+        {
+            AccountStatus::Contact => (),
+            AccountStatus::Blocked => {
+                refund_deposit(payer.as_ref());
+                return GroupUpdateResponse::Blocked;
+            }
+            // Groups we are already in can still be updated if the creator is no longer
+            // a contact (e.g. to remove us from the group).
+            AccountStatus::Unknown
+            | AccountStatus::ReceivedPendingRequest
+            | AccountStatus::SentPendingRequest => {
+                if previous_group.is_none() {
+                    refund_deposit(payer.as_ref());
+                    return GroupUpdateResponse::NotConnected;
+                }
+            }
+        }
+
+        let this = env::current_account_id();
+        let was_member = previous_group.map(|g| g.is_member(&this)).unwrap_or(false);
+        let is_member = group.is_member(&this);
+        let initial_storage = env::storage_usage();
+        self.groups.insert(&group_id, &group);
+        self.pay_storage_used(&creator, initial_storage, payer.as_ref());
+        if is_member && !was_member {
+            Event::group_member_added(&this, &group_id, &this).emit();
+        } else if was_member && !is_member {
+            Event::group_member_removed(&this, &group_id, &this).emit();
+        }
+
+        GroupUpdateResponse::Updated
+    }
+
+    /// Send a message to every member of a group. The attached deposit (or the caller's
+    /// storage balance) pays for storing the message in the members' contracts.
+    /// A member which charges more than the storage of the message (see
+    /// `view_message_deposit`) needs the deposit it asks for to be given in `deposits`; it is
+    /// forwarded to that member as-is instead of the storage cost.
+    /// Group messages can not be encrypted, since there is no single recipient key.
+    #[payable]
+    pub fn send_group_message(
+        &mut self,
+        group_id: GroupId,
+        message: MessageBody,
+        deposits: Option<HashMap<AccountId, U128>>,
+    ) -> MessageId {
+        let caller = self.require_permission(Permission::SendToContacts);
+
+        require!(
+            message.kind() != MessageKind::Encrypted,
+            "Group messages can not be encrypted"
+        );
+
+        let group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Unknown group"));
+        let this = env::current_account_id();
+        require!(group.is_member(&this), "You are not a member of this group");
+
+        let required_deposit = compute_required_message_deposit(&message);
+        let deposits = deposits.unwrap_or_default();
+        let recipients: Vec<(AccountId, Balance)> = group
+            .members
+            .into_iter()
+            .filter(|m| m != &this)
+            .map(|member| {
+                let deposit = deposits.get(&member).map_or(required_deposit, |d| d.0);
+                require!(
+                    deposit >= required_deposit,
+                    "The deposit must at least pay for the storage of the message"
+                );
+                (member, deposit)
+            })
+            .collect();
+        self.pay(&caller, recipients.iter().map(|(_, d)| d).sum(), None);
+
+        let group_message = Message {
+            sender: this.clone(),
+            timestamp: U64(env::block_timestamp()),
+            content: message.clone(),
+            parent_id: self.last_group_message.get(&group_id),
+            edited: false,
+            reply_to: None,
+            reactions: Vec::new(),
+        };
+        let message_id = group_message_id(&group_id, &group_message);
+        self.group_messages.insert(&message_id, &group_message);
+        self.last_group_message.insert(&group_id, &message_id);
+        Event::group_message_sent(&this, &group_id, &message_id, message.kind()).emit();
+
+        for (recipient, deposit) in recipients {
+            Self::ext(recipient.clone())
+                .with_attached_deposit(deposit)
+                .receive_group_message(group_id, message.clone(), message_id, Some(caller.clone()))
+                .then(Self::ext(this.clone()).send_group_message_callback(message_id, recipient));
+        }
+
+        message_id
+    }
+
+    /// Called by another member of a group when they send a message to it.
+    /// The message must meet our spam policy, including its price, as for messages from our
+    /// contacts.
+    #[payable]
+    pub fn receive_group_message(
+        &mut self,
+        group_id: GroupId,
+        content: MessageBody,
+        message_id: MessageId,
+        payer: Option<AccountId>,
+    ) -> MessageResponse {
+        let sender = env::predecessor_account_id();
+        if self.is_too_large(content.len()) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::TooLarge;
+        }
+        let price_multiplier = self.spam_policy.message_price_multiplier as Balance;
+        if !self.can_pay(&sender, self.required_message_deposit(&content)) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::InsufficientDeposit;
+        }
+
+        if let Some(AccountStatus::Blocked) = self.accounts.get(&sender) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::Blocked;
+        }
+        let this = env::current_account_id();
+        let is_connected = self
+            .groups
+            .get(&group_id)
+            .map(|g| g.is_member(&sender) && g.is_member(&this))
+            .unwrap_or(false);
+        if !is_connected {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::NotConnected;
+        }
+        if self.group_messages.get(&message_id).is_some() {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::DuplicateMessage;
+        }
+        let initial_storage = env::storage_usage();
+        let timestamp = env::block_timestamp();
+        if !self.try_count_message(&sender, timestamp) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::RateLimited;
+        }
+
+        let message = Message {
+            content,
+            sender: sender.clone(),
+            parent_id: self.last_group_message.get(&group_id),
+            timestamp: U64(timestamp),
+            edited: false,
+            reply_to: None,
+            reactions: Vec::new(),
+        };
+        self.group_messages.insert(&message_id, &message);
+        self.last_group_message.insert(&group_id, &message_id);
+        let used = env::storage_usage().saturating_sub(initial_storage);
+        self.pay(
+            &sender,
+            (used as Balance) * env::STORAGE_PRICE_PER_BYTE * price_multiplier,
+            payer.as_ref(),
+        );
+
+        Event::group_message_received(
+            &sender,
+            &this,
+            &group_id,
+            &message_id,
+            message.content.kind(),
+            self.is_muted(&sender),
+        )
+        .emit();
+
+        MessageResponse::Received
+    }
+
+    /// Record whether a group message reached one of the members.
+    #[private]
+    pub fn send_group_message_callback(
+        &mut self,
+        message_id: MessageId,
+        recipient: AccountId,
+        #[callback_result] response: Result<MessageResponse, PromiseError>,
+    ) -> MessageResponse {
+        let (status, response) = match response {
+            Ok(MessageResponse::Received) => (DeliveryStatus::Delivered, MessageResponse::Received),
+            Ok(other_response) => (
+                DeliveryStatus::Rejected {
+                    reason: other_response,
+                },
+                other_response,
+            ),
+            Err(_e) => (DeliveryStatus::Failed, MessageResponse::InvalidAccount),
+        };
+
+        let sender = env::current_account_id();
+        Event::message_delivery(&sender, &recipient, &message_id, &status).emit();
+
+        response
+    }
+}
+
+impl MessengerContract {
+    fn require_contact(&self, account: &AccountId) {
+        require!(
+            matches!(self.accounts.get(account), Some(AccountStatus::Contact)),
+            "Group members must be your contacts!"
+        );
+    }
+
+    fn get_own_group(&self, group_id: &GroupId) -> Group {
+        let group = self
+            .groups
+            .get(group_id)
+            .unwrap_or_else(|| env::panic_str("Unknown group"));
+        require!(
+            group.creator == env::current_account_id(),
+            "Only the creator of the group can change its members"
+        );
+        group
+    }
+
+    fn get_group_message(&self, id: &MessageId) -> Message {
+        self.group_messages
+            .get(id)
+            .unwrap_or_else(|| env::panic_str("Missing message"))
+    }
+
+    fn send_group_update(
+        &mut self,
+        payer: &AccountId,
+        group_id: GroupId,
+        group: &Group,
+        recipients: Vec<AccountId>,
+    ) {
+        let deposit_per_recipient = compute_required_group_deposit(group);
+        self.pay(
+            payer,
+            deposit_per_recipient * (recipients.len() as Balance),
+            None,
+        );
+        for recipient in recipients {
+            Self::ext(recipient)
+                .with_attached_deposit(deposit_per_recipient)
+                .ext_update_group(group_id, group.clone(), Some(payer.clone()));
+        }
+    }
+}
+
+fn compute_required_group_deposit(group: &Group) -> Balance {
+    let size = group
+        .try_to_vec()
+        .unwrap_or_else(|_e| env::panic_str("Failed to serialize group"))
+        .len();
+    (MESSAGE_STORAGE_OVERHEAD as Balance + size as Balance) * env::STORAGE_PRICE_PER_BYTE
+}
@@ -1,28 +1,58 @@
 use events::Event;
+use migration::MessageV0;
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::{LookupMap, UnorderedSet},
+    collections::{LookupMap, LookupSet, TreeMap, UnorderedMap, UnorderedSet, Vector},
     env,
-    json_types::U64,
+    json_types::{Base64VecU8, U128, U64},
     near_bindgen, require, AccountId, Balance, BorshStorageKey, PanicOnDefault, Promise,
-    PromiseError, PromiseOrValue,
+    PromiseError, PromiseOrValue, StorageUsage,
 };
+use purge::DEFAULT_PURGE_SIZE;
+use spam::MessageWindow;
+use storage::{refund, refund_deposit};
 use types::{
-    AcceptContactResponse, AccountStatus, AddContactResponse, Message, MessageId, MessageResponse,
-    MessageStatus, MessageWithId, UnreadMessageView,
+    AcceptContactResponse, AccountStatus, AddContactResponse, AutoReply, CancelContactResponse,
+    ContactMetadata, ConversationCursor, DeclineContactResponse, Delegate, DeliveryStatus,
+    EditMessageResponse, EncryptionKey, EncryptionKeyId, Group, GroupId, Message, MessageBody,
+    MessageId, MessageRequest, MessageRequestPolicy, MessageResponse, MessageRevision,
+    MessageStatus, MessageWithId, Page, Permission, Reaction, RemoveContactResponse,
+    RetractMessageResponse, SentMessage, SpamPolicy, Tombstone, UnreadMessageView,
+    ENCRYPTION_PUBLIC_KEY_LENGTH,
 };
 
+mod access;
+mod contacts;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod events;
+mod expiry;
+mod groups;
+mod message_requests;
+mod migration;
+mod purge;
+mod reactions;
+mod spam;
+mod storage;
 pub mod types;
 
 /// A deposit is required to send a contact request. This is meant to discourage spam and
-/// to cover the cost of inserting a storage key into another contract.
+/// to cover the cost of inserting a storage key into another contract. The receiving
+/// contract only keeps what it needs for the storage it actually uses.
+/// This is the default for the owner's `SpamPolicy`, which other contracts must meet.
 /// Note: 1 Near = 10^24 yoctoNear (the units of the Balance type).
-const ADD_CONTACT_DEPOSIT: Balance = env::STORAGE_PRICE_PER_BYTE;
+const ADD_CONTACT_DEPOSIT: Balance = 512 * env::STORAGE_PRICE_PER_BYTE;
+
+/// Upper bound on the storage a message uses in the receiving contract, apart from its content.
+const MESSAGE_STORAGE_OVERHEAD: StorageUsage = 1024;
 
 /// Number of messages shown in a view call by default.
 const DEFAULT_THREAD_SIZE: usize = 8;
 
+/// Number of earlier versions of a message kept when it is edited. The oldest one is dropped
+/// to make room for a new one.
+const MAX_MESSAGE_REVISIONS: usize = 10;
+
 /// Enum to different different sections of the contract storage.
 #[derive(BorshDeserialize, BorshSerialize, BorshStorageKey)]
 pub enum StoragePrefix {
@@ -31,6 +61,32 @@ pub enum StoragePrefix {
     MessageStatuses(MessageStatus),
     LastReceivedMessage,
     PendingContacts,
+    SentMessages,
+    LastSentMessage,
+    DeliveryStatuses,
+    LastAutoReply,
+    Groups,
+    GroupMessages,
+    LastGroupMessage,
+    MessageRevisions,
+    Tombstones,
+    SentTombstones,
+    ReadReceipts,
+    ReadReceiptsDisabled,
+    StorageBalances,
+    EncryptionKeys,
+    Reactions,
+    Replies,
+    StateVersion,
+    Delegates,
+    MessageRequests,
+    MessageWindows,
+    MessageExpiries,
+    Contacts,
+    ExpiryQueue,
+    MessageRequestIds,
+    ExpiryPayers,
+    ReceivedMessages,
 }
 
 #[near_bindgen]
@@ -38,27 +94,118 @@ pub enum StoragePrefix {
 pub struct MessengerContract {
     accounts: LookupMap<AccountId, AccountStatus>,
     messages: LookupMap<MessageId, Message>,
+    /// Messages received by the first release, in its layout, until they are changed (see
+    /// `migration`).
+    legacy_messages: LookupMap<MessageId, MessageV0>,
     unread_messages: UnorderedSet<MessageId>,
     read_messages: UnorderedSet<MessageId>,
     last_received_message: LookupMap<AccountId, MessageId>,
     pending_contacts: UnorderedSet<AccountId>,
+    sent_messages: LookupMap<MessageId, SentMessage>,
+    last_sent_message: LookupMap<AccountId, MessageId>,
+    delivery_statuses: LookupMap<MessageId, DeliveryStatus>,
+    auto_reply: Option<AutoReply>,
+    /// Time the current `auto_reply` was set.
+    auto_reply_set_at: u64,
+    /// Funds set aside by the owner to pay for the deposit of auto-reply messages.
+    auto_reply_budget: Balance,
+    /// Time we last sent an auto-reply to each account.
+    last_auto_reply: LookupMap<AccountId, u64>,
+    groups: UnorderedMap<GroupId, Group>,
+    group_messages: LookupMap<MessageId, Message>,
+    last_group_message: LookupMap<GroupId, MessageId>,
+    message_revisions: LookupMap<MessageId, Vec<MessageRevision>>,
+    /// Received messages that have been removed.
+    tombstones: LookupMap<MessageId, Tombstone>,
+    /// Sent messages that have been removed.
+    sent_tombstones: LookupMap<MessageId, Tombstone>,
+    /// Time our sent messages were read by their recipient.
+    read_receipts: LookupMap<MessageId, U64>,
+    /// Whether we tell senders when we read their messages.
+    read_receipts_enabled: bool,
+    /// Accounts we never send read receipts to, even if they are enabled.
+    read_receipts_disabled: LookupSet<AccountId>,
+    /// Balances pre-funded under the storage management standard.
+    storage_balances: LookupMap<AccountId, Balance>,
+    /// Public keys other accounts can encrypt messages for us with. The id of a key is its
+    /// index, and the last one is the key currently in use.
+    encryption_keys: Vector<Base64VecU8>,
+    /// Reactions to messages we sent or received, from either side of the conversation.
+    reactions: LookupMap<MessageId, Vec<Reaction>>,
+    /// Ids of the messages, sent or received, which reply to each message.
+    replies: LookupMap<MessageId, Vec<MessageId>>,
+    /// Accounts the owner allowed to use some of the owner-only methods.
+    delegates: UnorderedMap<AccountId, Delegate>,
+    /// Terms for accounts which are not our contacts to send us a message request, if allowed.
+    message_request_policy: Option<MessageRequestPolicy>,
+    /// Messages from accounts which are not our contacts, until we accept or decline them.
+    message_requests: UnorderedMap<AccountId, MessageRequest>,
+    /// Ids of the messages in `message_requests`.
+    message_request_ids: LookupSet<MessageId>,
+    spam_policy: SpamPolicy,
+    /// Messages each account sent us recently, to enforce the rate limit of the `spam_policy`.
+    message_windows: LookupMap<AccountId, MessageWindow>,
+    /// Time messages we sent or received with a time-to-live expire.
+    message_expiries: UnorderedMap<MessageId, u64>,
+    /// Metadata the owner keeps about each of our contacts.
+    contacts: UnorderedMap<AccountId, ContactMetadata>,
+    /// The messages in `message_expiries`, by the time they expire.
+    expiry_queue: TreeMap<u64, Vec<MessageId>>,
+    /// Accounts which paid for received messages in `message_expiries` on behalf of their
+    /// sender, to refund when the messages expire.
+    expiry_payers: LookupMap<MessageId, AccountId>,
     owner: AccountId,
+    /// Account which can accept the ownership of the contract.
+    proposed_owner: Option<AccountId>,
 }
 
 #[near_bindgen]
 impl MessengerContract {
     #[init]
     pub fn new() -> Self {
+        migration::write_state_version(migration::CURRENT_STATE_VERSION);
         Self {
             accounts: LookupMap::new(StoragePrefix::Accounts),
-            messages: LookupMap::new(StoragePrefix::Messages),
+            messages: LookupMap::new(StoragePrefix::ReceivedMessages),
+            legacy_messages: LookupMap::new(StoragePrefix::Messages),
             unread_messages: UnorderedSet::new(StoragePrefix::MessageStatuses(
                 MessageStatus::Unread,
             )),
             read_messages: UnorderedSet::new(StoragePrefix::MessageStatuses(MessageStatus::Read)),
             last_received_message: LookupMap::new(StoragePrefix::LastReceivedMessage),
             pending_contacts: UnorderedSet::new(StoragePrefix::PendingContacts),
+            sent_messages: LookupMap::new(StoragePrefix::SentMessages),
+            last_sent_message: LookupMap::new(StoragePrefix::LastSentMessage),
+            delivery_statuses: LookupMap::new(StoragePrefix::DeliveryStatuses),
+            auto_reply: None,
+            auto_reply_set_at: 0,
+            auto_reply_budget: 0,
+            last_auto_reply: LookupMap::new(StoragePrefix::LastAutoReply),
+            groups: UnorderedMap::new(StoragePrefix::Groups),
+            group_messages: LookupMap::new(StoragePrefix::GroupMessages),
+            last_group_message: LookupMap::new(StoragePrefix::LastGroupMessage),
+            message_revisions: LookupMap::new(StoragePrefix::MessageRevisions),
+            tombstones: LookupMap::new(StoragePrefix::Tombstones),
+            sent_tombstones: LookupMap::new(StoragePrefix::SentTombstones),
+            read_receipts: LookupMap::new(StoragePrefix::ReadReceipts),
+            read_receipts_enabled: true,
+            read_receipts_disabled: LookupSet::new(StoragePrefix::ReadReceiptsDisabled),
+            storage_balances: LookupMap::new(StoragePrefix::StorageBalances),
+            encryption_keys: Vector::new(StoragePrefix::EncryptionKeys),
+            reactions: LookupMap::new(StoragePrefix::Reactions),
+            replies: LookupMap::new(StoragePrefix::Replies),
+            delegates: UnorderedMap::new(StoragePrefix::Delegates),
+            message_request_policy: None,
+            message_requests: UnorderedMap::new(StoragePrefix::MessageRequests),
+            message_request_ids: LookupSet::new(StoragePrefix::MessageRequestIds),
+            spam_policy: SpamPolicy::default(),
+            message_windows: LookupMap::new(StoragePrefix::MessageWindows),
+            message_expiries: UnorderedMap::new(StoragePrefix::MessageExpiries),
+            contacts: UnorderedMap::new(StoragePrefix::Contacts),
+            expiry_queue: TreeMap::new(StoragePrefix::ExpiryQueue),
+            expiry_payers: LookupMap::new(StoragePrefix::ExpiryPayers),
             owner: env::predecessor_account_id(),
+            proposed_owner: None,
         }
     }
 
@@ -66,116 +213,401 @@ impl MessengerContract {
     /// Note: this function does not mutate the contract. Therefore it can be done as a
     /// view call, but also will not mark unread messages as read.
     pub fn view_message(&self, message_id: MessageId) -> Option<Message> {
-        self.messages.get(&message_id)
+        if self.is_expired(&message_id) {
+            return None;
+        }
+        let mut message = self.received_message(&message_id)?;
+        message.reactions = self.reactions_of(&message_id);
+        Some(message)
     }
 
-    pub fn view_unread(&self, max_size: Option<usize>) -> Vec<UnreadMessageView> {
-        let unread_set = &self.unread_messages;
-        let num_messages = unread_set.len() as usize;
-        let num_to_view = max_size.unwrap_or(num_messages).min(num_messages);
-        let mut result = Vec::with_capacity(num_to_view);
-        for id in unread_set.iter().take(num_to_view) {
-            let message = self.get_message(&id);
-            let view = UnreadMessageView {
-                id,
-                sender: message.sender,
-                timestamp: message.timestamp,
-            };
-            result.push(view);
+    /// View a single message we sent.
+    pub fn view_sent_message(&self, message_id: MessageId) -> Option<SentMessage> {
+        if self.is_expired(&message_id) {
+            return None;
+        }
+        let mut message = self.sent_messages.get(&message_id)?;
+        message.reactions = self.reactions_of(&message_id);
+        Some(message)
+    }
+
+    /// View the earlier versions of the content of a message we received, oldest first.
+    pub fn view_message_revisions(&self, message_id: MessageId) -> Vec<MessageRevision> {
+        if self.is_expired(&message_id) {
+            return Vec::new();
         }
-        result
+        self.message_revisions.get(&message_id).unwrap_or_default()
+    }
+
+    /// View what remains of a received message that was removed.
+    pub fn view_tombstone(&self, message_id: MessageId) -> Option<Tombstone> {
+        self.tombstones.get(&message_id)
+    }
+
+    /// View when a message we sent was read by its recipient, if they sent a read receipt.
+    pub fn view_read_receipt(&self, message_id: MessageId) -> Option<U64> {
+        self.read_receipts.get(&message_id)
+    }
+
+    /// Whether we send read receipts, either in general or to a particular `account`.
+    pub fn view_read_receipts_enabled(&self, account: Option<AccountId>) -> bool {
+        match account {
+            Some(account) => {
+                self.read_receipts_enabled && !self.read_receipts_disabled.contains(&account)
+            }
+            None => self.read_receipts_enabled,
+        }
+    }
+
+    /// View one of our published encryption keys, or the one currently in use if no
+    /// `key_id` is given. Messages for us should be encrypted with the current key.
+    pub fn view_encryption_key(&self, key_id: Option<EncryptionKeyId>) -> Option<EncryptionKey> {
+        let id = match key_id {
+            Some(id) => id,
+            None => (self.encryption_keys.len() as EncryptionKeyId).checked_sub(1)?,
+        };
+        self.encryption_keys
+            .get(id as u64)
+            .map(|public_key| EncryptionKey { id, public_key })
+    }
+
+    /// View whether a message we sent was delivered.
+    pub fn view_delivery_status(&self, message_id: MessageId) -> Option<DeliveryStatus> {
+        self.delivery_statuses.get(&message_id)
+    }
+
+    pub fn view_auto_reply(&self) -> Option<AutoReply> {
+        self.auto_reply.clone()
+    }
+
+    pub fn view_auto_reply_budget(&self) -> U128 {
+        U128(self.auto_reply_budget)
+    }
+
+    /// View the messages we have not read yet, starting at `from_index` (the first one
+    /// by default). Expired messages are left out, so a page may have fewer than `max_size`.
+    pub fn view_unread(
+        &self,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<UnreadMessageView, u64> {
+        let unread_set = &self.unread_messages;
+        let Page { items, next } =
+            page_from_index(unread_set.iter(), unread_set.len(), from_index, max_size);
+        let items = items
+            .into_iter()
+            .filter(|id| !self.is_expired(id))
+            .map(|id| {
+                let message = self.get_message(&id);
+                UnreadMessageView {
+                    id,
+                    sender: message.sender,
+                    timestamp: message.timestamp,
+                }
+            })
+            .collect();
+        Page { items, next }
     }
 
     /// Shows the history of messages we have received from the given `sender`.
     /// This method only shows the received messages (i.e. only half-the conversation).
-    /// EXERCISE: Write a function that shows both sides of the conversation. This could be an on-chain or off-chain function.
-    pub fn view_thread(&self, sender: AccountId, max_size: Option<usize>) -> Vec<MessageWithId> {
+    /// See `view_conversation` for both sides.
+    /// Only messages older than `before` are shown, if it is given. The page is empty if
+    /// `before` is not a message of this thread, or has been purged.
+    pub fn view_thread(
+        &self,
+        sender: AccountId,
+        max_size: Option<usize>,
+        before: Option<MessageId>,
+    ) -> Page<MessageWithId, MessageId> {
         let max_size = max_size.unwrap_or(DEFAULT_THREAD_SIZE);
-        let last_message = match self.last_received_message.get(&sender) {
-            Some(id) => id,
-            None => return Vec::new(),
+        let start = match before {
+            Some(id) => match self.received_parent_id(&id, &sender) {
+                Some(parent_id) => parent_id,
+                None => return Page::default(),
+            },
+            None => self.last_received_message.get(&sender),
         };
-        let mut result = Vec::with_capacity(max_size);
-        let mut current_message = MessageWithId {
-            id: last_message,
-            message: self.get_message(&last_message),
-        };
-        for _ in 0..max_size {
-            let next_message = current_message.message.parent_id;
+        let mut result: Vec<MessageWithId> = Vec::with_capacity(max_size);
+        let mut next_message = self.next_received_message(start);
+        while result.len() < max_size {
+            let Some(current_message) = next_message.take() else {
+                break;
+            };
+            next_message = self.next_received_message(current_message.message.parent_id);
             result.push(current_message);
-            match next_message {
-                Some(id) => {
-                    current_message = MessageWithId {
-                        id,
-                        message: self.get_message(&id),
-                    };
-                }
-                None => break,
-            }
         }
+        let next = next_message.and(result.last().map(|m| m.id));
         // We read the thread from most recent to least, so we reverse the order
         // for the benefit of the user.
         result.reverse();
-        result
+        Page {
+            items: result,
+            next,
+        }
     }
 
-    pub fn view_pending_contacts(&self, max_size: Option<usize>) -> Vec<AccountId> {
-        match max_size {
-            Some(size) => self.pending_contacts.iter().take(size).collect(),
-            None => self.pending_contacts.iter().collect(),
+    /// Shows the history of messages exchanged with the given `account`, both sent and
+    /// received, ordered by timestamp. Messages we sent have our account as the `sender`.
+    /// Only messages older than the ones in `before` are shown, if it is given. The page is
+    /// empty if either message in `before` is not part of this conversation, or has been purged.
+    pub fn view_conversation(
+        &self,
+        account: AccountId,
+        max_size: Option<usize>,
+        before: Option<ConversationCursor>,
+    ) -> Page<MessageWithId, ConversationCursor> {
+        let max_size = max_size.unwrap_or(DEFAULT_THREAD_SIZE);
+        let this = env::current_account_id();
+        let mut cursor = before.unwrap_or_default();
+        let start_received = match &cursor.received {
+            Some(id) => match self.received_parent_id(id, &account) {
+                Some(parent_id) => parent_id,
+                None => return Page::default(),
+            },
+            None => self.last_received_message.get(&account),
+        };
+        let start_sent = match &cursor.sent {
+            Some(id) => match self.sent_parent_id(id, &account) {
+                Some(parent_id) => parent_id,
+                None => return Page::default(),
+            },
+            None => self.last_sent_message.get(&account),
+        };
+        let mut next_received = self.next_received_message(start_received);
+        let mut next_sent = self.next_sent_message(start_sent, &this);
+        let mut result = Vec::with_capacity(max_size);
+        // Walk both chains from the most recent message, always taking the newer of the two.
+        while result.len() < max_size {
+            let take_received = match (&next_received, &next_sent) {
+                (Some(received), Some(sent)) => {
+                    received.message.timestamp.0 >= sent.message.timestamp.0
+                }
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_received {
+                let current = next_received.take().unwrap();
+                next_received = self.next_received_message(current.message.parent_id);
+                cursor.received = Some(current.id);
+                result.push(current);
+            } else {
+                let current = next_sent.take().unwrap();
+                next_sent = self.next_sent_message(current.message.parent_id, &this);
+                cursor.sent = Some(current.id);
+                result.push(current);
+            }
+        }
+        result.reverse();
+        let has_more = next_received.is_some() || next_sent.is_some();
+        Page {
+            items: result,
+            next: has_more.then_some(cursor),
         }
     }
 
+    /// Shows the replies to a message we sent or received, oldest first. Replies we sent
+    /// have our account as the `sender`. Replies which have been removed are not shown.
+    pub fn view_replies(
+        &self,
+        message_id: MessageId,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<MessageWithId, u64> {
+        let this = env::current_account_id();
+        let ids = self.replies.get(&message_id).unwrap_or_default();
+        let len = ids.len() as u64;
+        let Page { items, next } = page_from_index(ids.into_iter(), len, from_index, max_size);
+        Page {
+            items: items
+                .into_iter()
+                .filter_map(|id| self.find_message(id, &this))
+                .collect(),
+            next,
+        }
+    }
+
+    pub fn view_pending_contacts(
+        &self,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<AccountId, u64> {
+        page_from_index(
+            self.pending_contacts.iter(),
+            self.pending_contacts.len(),
+            from_index,
+            max_size,
+        )
+    }
+
     /// In contrast to `view_message`, this function actually marks the message as read.
     /// Therefore, this must be done as a real transaction, not just a view call.
+    /// If read receipts are enabled then the sender is told the message was read.
     pub fn read_message(&mut self, message_id: MessageId) -> Option<Message> {
-        self.require_owner_only();
+        self.require_permission(Permission::ReadOnly);
 
         let was_unread = self.unread_messages.remove(&message_id);
+        let message = self.view_message(message_id);
         if was_unread {
             self.read_messages.insert(&message_id);
+            if let Some(message) = &message {
+                let is_blocked = matches!(
+                    self.accounts.get(&message.sender),
+                    Some(AccountStatus::Blocked)
+                );
+                if !is_blocked && self.view_read_receipts_enabled(Some(message.sender.clone())) {
+                    Self::ext(message.sender.clone())
+                        .ext_message_read(message_id, U64(env::block_timestamp()));
+                }
+            }
         }
-        self.messages.get(&message_id)
+        message
     }
 
-    /// Send a message to one of your contacts.
-    #[payable]
-    pub fn send_message(&mut self, account: AccountId, message: String) -> Promise {
+    /// Turn read receipts on or off. If an `account` is given then the setting only applies
+    /// to that account, otherwise it applies to all accounts.
+    pub fn set_read_receipts(&mut self, enabled: bool, account: Option<AccountId>) {
         self.require_owner_only();
 
-        let required_deposit = compute_required_message_deposit(&message);
-        let deposit = env::attached_deposit();
-        require!(deposit >= required_deposit, "Insufficient deposit");
+        match account {
+            Some(account) => {
+                if enabled {
+                    self.read_receipts_disabled.remove(&account);
+                } else {
+                    self.read_receipts_disabled.insert(&account);
+                }
+            }
+            None => self.read_receipts_enabled = enabled,
+        }
+    }
+
+    /// Publish a new encryption key, which becomes the one other accounts should use.
+    /// Earlier keys stay published so that messages encrypted with them can still be
+    /// decrypted.
+    pub fn set_encryption_key(&mut self, public_key: Base64VecU8) -> EncryptionKeyId {
+        self.require_owner_only();
+
+        require!(
+            public_key.0.len() == ENCRYPTION_PUBLIC_KEY_LENGTH,
+            "Invalid encryption public key"
+        );
+        self.encryption_keys.push(&public_key);
+        (self.encryption_keys.len() - 1) as EncryptionKeyId
+    }
+
+    /// Called by another Messenger contract when it has read a message we sent it.
+    pub fn ext_message_read(&mut self, message_id: MessageId, read_at: U64) {
+        let reader = env::predecessor_account_id();
+        let is_recipient = self
+            .sent_messages
+            .get(&message_id)
+            .map(|m| m.recipient == reader)
+            .unwrap_or(false);
+        if !is_recipient || self.read_receipts.get(&message_id).is_some() {
+            return;
+        }
+        self.read_receipts.insert(&message_id, &read_at);
+
+        let sender = env::current_account_id();
+        Event::message_read(&sender, &reader, &message_id).emit();
+    }
+
+    /// `send_message` flow:
+    /// 1. Record the message in our outbox and call `receive_message` in the recipient's account.
+    /// 2. Record whether the message was delivered in a callback.
+    /// The deposit for the recipient comes from the attached deposit or the caller's
+    /// storage balance. Whatever the recipient does not use is credited to the caller's
+    /// storage balance. A recipient which charges more than the storage of the message
+    /// (see `view_message_deposit`) needs the `deposit` it asks for to be given; it is
+    /// forwarded as-is instead of the storage cost.
+    /// The message can be a reply to any message we sent to or received from the recipient.
+    /// If a `ttl` (in nanoseconds) is given then the message expires that long after it is
+    /// sent, on both sides.
+    #[payable]
+    pub fn send_message(
+        &mut self,
+        account: AccountId,
+        message: MessageBody,
+        reply_to: Option<MessageId>,
+        ttl: Option<U64>,
+        deposit: Option<U128>,
+    ) -> Promise {
+        let caller = self.require_permission(Permission::SendToContacts);
 
         require!(
             matches!(self.accounts.get(&account), Some(AccountStatus::Contact)),
             "You can only send messages to your contacts!"
         );
+        if let Some(reply_to) = &reply_to {
+            require!(
+                self.is_message_with(&account, reply_to),
+                "You can only reply to messages from the same conversation"
+            );
+        }
 
-        let sender = env::current_account_id();
-        Event::message_sent(&sender, &account).emit();
+        let required_deposit = compute_required_message_deposit(&message);
+        let deposit = match deposit {
+            Some(U128(deposit)) => {
+                require!(
+                    deposit >= required_deposit,
+                    "The deposit must at least pay for the storage of the message"
+                );
+                deposit
+            }
+            None => required_deposit,
+        };
+        self.pay(&caller, deposit, None);
 
-        Self::ext(account)
-            .with_attached_deposit(deposit)
-            .receive_message(message)
+        self.send_message_internal(account, message, reply_to, ttl, deposit, Some(caller))
     }
 
     /// Called by another Messenger contract when their user wants to send us a message.
     /// The functionality of this method is minimal: it checks a few preconditions then
     /// persists the message.
-    /// EXERCISE: Add functionality where it is possible to set an auto-reply on receiving a message.
+    /// The sender may give the `message_id` it recorded the message under, so that both
+    /// contracts refer to the message by the same id. An id we already use for another
+    /// message, sent or received, is rejected as a duplicate.
+    /// The part of the deposit which is not used is refunded to the storage balance of the
+    /// `payer` with the sender, if the sender names one (see `storage`).
+    /// If an auto-reply is set then it is sent back, unless this message is an auto-reply itself.
+    /// A message sent with a time-to-live expires at the `expires_at` time the sender gives
+    /// (in nanoseconds since the Unix epoch), so that it expires at the same time on both sides.
     #[payable]
-    pub fn receive_message(&mut self, content: String) -> MessageResponse {
-        let required_deposit = compute_required_message_deposit(&content);
-        let deposit = env::attached_deposit();
-        if deposit < required_deposit {
+    pub fn receive_message(
+        &mut self,
+        content: MessageBody,
+        message_id: Option<MessageId>,
+        is_auto_reply: Option<bool>,
+        reply_to: Option<MessageId>,
+        expires_at: Option<U64>,
+        payer: Option<AccountId>,
+    ) -> MessageResponse {
+        let sender = env::predecessor_account_id();
+        if self.is_too_large(content.len()) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::TooLarge;
+        }
+        let price_multiplier = self.spam_policy.message_price_multiplier as Balance;
+        if !self.can_pay(&sender, self.required_message_deposit(&content)) {
+            refund_deposit(payer.as_ref());
             return MessageResponse::InsufficientDeposit;
         }
 
-        let sender = env::predecessor_account_id();
         let status = self.accounts.get(&sender).unwrap_or(AccountStatus::Unknown);
         match status {
             AccountStatus::Contact => {
+                if !self.is_known_encryption_key(&content) {
+                    refund_deposit(payer.as_ref());
+                    return MessageResponse::UnknownEncryptionKey;
+                }
+                if let Some(reply_to) = &reply_to {
+                    if !self.is_message_with(&sender, reply_to) {
+                        refund_deposit(payer.as_ref());
+                        return MessageResponse::UnknownReplyTo;
+                    }
+                }
+                let initial_storage = env::storage_usage();
                 let parent_id = self.last_received_message.get(&sender);
                 let timestamp = env::block_timestamp();
                 let message = Message {
@@ -183,24 +615,302 @@ impl MessengerContract {
                     sender: sender.clone(),
                     parent_id,
                     timestamp: U64(timestamp),
+                    edited: false,
+                    reply_to,
+                    reactions: Vec::new(),
                 };
-                let message_id = message.id();
+                let message_id = message_id.unwrap_or_else(|| message.id());
+                if self.is_known_message_id(&message_id) {
+                    refund_deposit(payer.as_ref());
+                    return MessageResponse::DuplicateMessage;
+                }
+                if !self.try_count_message(&sender, timestamp) {
+                    refund_deposit(payer.as_ref());
+                    return MessageResponse::RateLimited;
+                }
                 self.messages.insert(&message_id, &message);
-                self.unread_messages.insert(&message_id);
+                if self.is_muted(&sender) {
+                    self.read_messages.insert(&message_id);
+                } else {
+                    self.unread_messages.insert(&message_id);
+                }
                 self.last_received_message.insert(&sender, &message_id);
+                if let Some(reply_to) = &reply_to {
+                    self.add_reply(reply_to, message_id);
+                }
+                self.set_message_expiry(&message_id, expires_at, payer.as_ref());
+                let used = env::storage_usage().saturating_sub(initial_storage);
+                self.pay(
+                    &sender,
+                    (used as Balance) * env::STORAGE_PRICE_PER_BYTE * price_multiplier,
+                    payer.as_ref(),
+                );
 
                 let receiver = env::current_account_id();
-                Event::message_received(&sender, &receiver, &message_id).emit();
+                Event::message_received(&sender, &receiver, &message_id, message.content.kind())
+                    .emit();
+
+                if !is_auto_reply.unwrap_or(false) {
+                    self.try_auto_reply(sender);
+                }
 
                 MessageResponse::Received
             }
-            AccountStatus::Blocked => MessageResponse::Blocked,
+            AccountStatus::Blocked => {
+                refund_deposit(payer.as_ref());
+                MessageResponse::Blocked
+            }
             AccountStatus::Unknown
             | AccountStatus::ReceivedPendingRequest
-            | AccountStatus::SentPendingRequest => MessageResponse::NotConnected,
+            | AccountStatus::SentPendingRequest => {
+                refund_deposit(payer.as_ref());
+                MessageResponse::NotConnected
+            }
         }
     }
 
+    /// Block an account. Any pending contact request from the account is dropped, and
+    /// future messages and contact requests from it are rejected.
+    pub fn block_account(&mut self, account: AccountId) {
+        self.require_permission(Permission::ManageContacts);
+
+        self.accounts.insert(&account, &AccountStatus::Blocked);
+        self.contacts.remove(&account);
+        self.message_windows.remove(&account);
+        self.pending_contacts.remove(&account);
+        self.remove_message_request(&account, true);
+
+        let this = env::current_account_id();
+        Event::account_blocked(&this, &account).emit();
+    }
+
+    /// Unblock a previously blocked account. The account returns to the `Unknown` status,
+    /// so it must go through the `add_contact` flow again to become a contact.
+    pub fn unblock_account(&mut self, account: AccountId) {
+        self.require_permission(Permission::ManageContacts);
+
+        require!(
+            matches!(self.accounts.get(&account), Some(AccountStatus::Blocked)),
+            "Account is not blocked"
+        );
+        self.accounts.remove(&account);
+
+        let this = env::current_account_id();
+        Event::account_unblocked(&this, &account).emit();
+    }
+
+    /// `edit_message` flow:
+    /// 1. Call `ext_edit_message` in the account we sent the message to.
+    ///    The account keeps the previous content of the message as a revision.
+    /// 2. Update our own copy of the message in a callback if the edit was accepted.
+    /// The deposit is paid as for `send_message`: a recipient which charges more than the
+    /// storage of the new content needs the `deposit` it asks for to be given.
+    #[payable]
+    pub fn edit_message(
+        &mut self,
+        account: AccountId,
+        message_id: MessageId,
+        new_content: MessageBody,
+        deposit: Option<U128>,
+    ) -> Promise {
+        let caller = self.require_permission(Permission::SendToContacts);
+
+        let sent_message = self
+            .sent_messages
+            .get(&message_id)
+            .unwrap_or_else(|| env::panic_str("Unknown message"));
+        require!(
+            sent_message.recipient == account,
+            "The message was not sent to this account"
+        );
+        let required_deposit = compute_required_edit_deposit(&new_content);
+        let deposit = match deposit {
+            Some(U128(deposit)) => {
+                require!(
+                    deposit >= required_deposit,
+                    "The deposit must at least pay for the storage of the edit"
+                );
+                deposit
+            }
+            None => required_deposit,
+        };
+        self.pay(&caller, deposit, None);
+
+        let this = env::current_account_id();
+        Self::ext(account)
+            .with_attached_deposit(deposit)
+            .ext_edit_message(message_id, new_content.clone(), Some(caller))
+            .then(Self::ext(this).edit_message_callback(message_id, new_content))
+    }
+
+    /// Part of the `edit_message` flow. This method is called by another Messenger contract
+    /// when it wants to change the content of a message it sent us. The sender pays for the
+    /// storage the edit uses, including the revision kept of the previous content, at the
+    /// price of our `SpamPolicy`. Edits count towards its rate limit like messages.
+    /// Only the latest `MAX_MESSAGE_REVISIONS` revisions are kept.
+    #[payable]
+    pub fn ext_edit_message(
+        &mut self,
+        message_id: MessageId,
+        new_content: MessageBody,
+        payer: Option<AccountId>,
+    ) -> EditMessageResponse {
+        let sender = env::predecessor_account_id();
+        if let Some(AccountStatus::Blocked) = self.accounts.get(&sender) {
+            refund_deposit(payer.as_ref());
+            return EditMessageResponse::Blocked;
+        }
+        let mut message = match self.received_message(&message_id) {
+            Some(message) => message,
+            None => {
+                refund_deposit(payer.as_ref());
+                return EditMessageResponse::UnknownMessage;
+            }
+        };
+        if message.sender != sender {
+            refund_deposit(payer.as_ref());
+            return EditMessageResponse::NotSender;
+        }
+        if self.is_too_large(new_content.len()) {
+            refund_deposit(payer.as_ref());
+            return EditMessageResponse::TooLarge;
+        }
+        let price_multiplier = self.spam_policy.message_price_multiplier as Balance;
+        if !self.can_pay(&sender, self.required_message_deposit(&new_content)) {
+            refund_deposit(payer.as_ref());
+            return EditMessageResponse::InsufficientDeposit;
+        }
+        if !self.is_known_encryption_key(&new_content) {
+            refund_deposit(payer.as_ref());
+            return EditMessageResponse::UnknownEncryptionKey;
+        }
+
+        let initial_storage = env::storage_usage();
+        if !self.try_count_message(&sender, env::block_timestamp()) {
+            refund_deposit(payer.as_ref());
+            return EditMessageResponse::RateLimited;
+        }
+        let kind = new_content.kind();
+        let mut revisions = self.message_revisions.get(&message_id).unwrap_or_default();
+        if revisions.len() >= MAX_MESSAGE_REVISIONS {
+            revisions.drain(..=revisions.len() - MAX_MESSAGE_REVISIONS);
+        }
+        revisions.push(MessageRevision {
+            content: std::mem::replace(&mut message.content, new_content),
+            replaced_at: U64(env::block_timestamp()),
+        });
+        message.edited = true;
+        self.message_revisions.insert(&message_id, &revisions);
+        self.insert_received_message(&message_id, &message);
+        let used = env::storage_usage().saturating_sub(initial_storage);
+        self.pay(
+            &sender,
+            (used as Balance) * env::STORAGE_PRICE_PER_BYTE * price_multiplier,
+            payer.as_ref(),
+        );
+
+        let receiver = env::current_account_id();
+        Event::message_edited(&sender, &receiver, &message_id, kind).emit();
+
+        EditMessageResponse::Edited
+    }
+
+    /// `retract_message` flow:
+    /// 1. Call `ext_retract_message` in the account we sent the message to.
+    ///    The account removes the message and refunds its storage deposit to the caller's
+    ///    storage balance.
+    /// 2. Remove our own copy of the message in a callback if the retraction was accepted.
+    pub fn retract_message(&mut self, account: AccountId, message_id: MessageId) -> Promise {
+        let caller = self.require_permission(Permission::SendToContacts);
+
+        let sent_message = self
+            .sent_messages
+            .get(&message_id)
+            .unwrap_or_else(|| env::panic_str("Unknown message"));
+        require!(
+            sent_message.recipient == account,
+            "The message was not sent to this account"
+        );
+
+        let this = env::current_account_id();
+        Self::ext(account)
+            .ext_retract_message(message_id, Some(caller))
+            .then(Self::ext(this).retract_message_callback(message_id))
+    }
+
+    /// Part of the `retract_message` flow. This method is called by another Messenger contract
+    /// when it wants to remove a message it sent us. A tombstone is left in place of the
+    /// message so that the rest of the thread can still be viewed. The storage the sender
+    /// paid for is refunded to the storage balance of the `payer` with the sender, if the
+    /// sender names one (see `storage`).
+    pub fn ext_retract_message(
+        &mut self,
+        message_id: MessageId,
+        payer: Option<AccountId>,
+    ) -> RetractMessageResponse {
+        let sender = env::predecessor_account_id();
+        let message = match self.received_message(&message_id) {
+            Some(message) => message,
+            None => return RetractMessageResponse::UnknownMessage,
+        };
+        if message.sender != sender {
+            return RetractMessageResponse::NotSender;
+        }
+
+        let freed = self.replace_with_tombstone(&message_id, message, env::block_timestamp());
+
+        let receiver = env::current_account_id();
+        Event::message_retracted(&sender, &receiver, &message_id).emit();
+
+        refund(
+            (freed as Balance) * env::STORAGE_PRICE_PER_BYTE,
+            payer.as_ref(),
+        );
+
+        RetractMessageResponse::Retracted
+    }
+
+    /// Set a message to automatically reply with when receiving messages. Passing `None`
+    /// turns the auto-reply off. Auto-replies are paid for from the budget funded by
+    /// `fund_auto_reply`; if the budget runs out then no auto-reply is sent.
+    pub fn set_auto_reply(&mut self, auto_reply: Option<AutoReply>) {
+        self.require_owner_only();
+
+        if let Some(AutoReply {
+            start: Some(start),
+            end: Some(end),
+            ..
+        }) = &auto_reply
+        {
+            require!(start.0 < end.0, "Auto-reply must start before it ends");
+        }
+        self.auto_reply = auto_reply;
+        self.auto_reply_set_at = env::block_timestamp();
+    }
+
+    /// Add the attached deposit to the auto-reply budget. Returns the new budget.
+    #[payable]
+    pub fn fund_auto_reply(&mut self) -> U128 {
+        self.require_owner_only();
+
+        self.auto_reply_budget += env::attached_deposit();
+        U128(self.auto_reply_budget)
+    }
+
+    /// Return funds from the auto-reply budget to the owner. The whole budget is
+    /// withdrawn if no `amount` is given.
+    pub fn withdraw_auto_reply_budget(&mut self, amount: Option<U128>) -> Promise {
+        let owner = self.require_owner_only();
+
+        let amount = amount.map(|a| a.0).unwrap_or(self.auto_reply_budget);
+        require!(
+            amount <= self.auto_reply_budget,
+            "Amount exceeds the auto-reply budget"
+        );
+        self.auto_reply_budget -= amount;
+        Promise::new(owner).transfer(amount)
+    }
+
     /// `add_contact` flow:
     /// 1. Call `ext_add_contact` in the account we wish to add as a contact.
     ///    This ensures the account understands the Messenger protocol and that they
@@ -208,34 +918,39 @@ impl MessengerContract {
     /// 2. Check the response from the account in a callback.
     #[payable]
     pub fn add_contact(&mut self, account: AccountId) -> Promise {
-        self.require_owner_only();
+        let caller = self.require_permission(Permission::ManageContacts);
 
-        let deposit = env::attached_deposit();
-        require!(deposit >= ADD_CONTACT_DEPOSIT, "Insufficient deposit");
+        require!(
+            !matches!(self.accounts.get(&account), Some(AccountStatus::Blocked)),
+            "Unblock the account before adding it as a contact"
+        );
+        self.pay(&caller, ADD_CONTACT_DEPOSIT, None);
 
         let this = env::current_account_id();
         Self::ext(account.clone())
-            .with_attached_deposit(deposit)
-            .ext_add_contact()
+            .with_attached_deposit(ADD_CONTACT_DEPOSIT)
+            .ext_add_contact(Some(caller))
             .then(Self::ext(this).add_contact_callback(account))
     }
 
     /// Part of the `add_contact` flow. This method is called by another Messenger contract
     /// when it wants to add us as a contact. If we don't know this account then we add
     /// that we have received a pending request (which we may choose to accept).
+    /// Only the storage used by the request is paid for; the rest of the deposit is refunded.
     #[payable]
-    pub fn ext_add_contact(&mut self) -> AddContactResponse {
-        let deposit = env::attached_deposit();
-        if deposit < ADD_CONTACT_DEPOSIT {
+    pub fn ext_add_contact(&mut self, payer: Option<AccountId>) -> AddContactResponse {
+        let request_sender = env::predecessor_account_id();
+        if !self.can_pay(&request_sender, self.spam_policy.contact_request_deposit.0) {
+            refund_deposit(payer.as_ref());
             return AddContactResponse::InsufficientDeposit;
         }
 
-        let request_sender = env::predecessor_account_id();
         let current_status = self
             .accounts
             .get(&request_sender)
             .unwrap_or(AccountStatus::Unknown);
-        match current_status {
+        let initial_storage = env::storage_usage();
+        let response = match current_status {
             AccountStatus::Unknown => {
                 self.accounts
                     .insert(&request_sender, &AccountStatus::ReceivedPendingRequest);
@@ -248,8 +963,7 @@ impl MessengerContract {
             }
             AccountStatus::SentPendingRequest => {
                 // We had sent a contact request and they added us back, so let's accept
-                self.accounts
-                    .insert(&request_sender, &AccountStatus::Contact);
+                self.insert_contact(&request_sender);
                 self.pending_contacts.remove(&request_sender);
 
                 let this = env::current_account_id();
@@ -260,7 +974,9 @@ impl MessengerContract {
             AccountStatus::ReceivedPendingRequest => AddContactResponse::Pending,
             AccountStatus::Blocked => AddContactResponse::Blocked,
             AccountStatus::Contact => AddContactResponse::AlreadyConnected,
-        }
+        };
+        self.pay_storage_used(&request_sender, initial_storage, payer.as_ref());
+        response
     }
 
     /// `accept_contact` flow:
@@ -268,7 +984,7 @@ impl MessengerContract {
     /// 2. Call `ext_accept_contact` in the other account, to communicate the request is accepted.
     /// 3. Check the response from the account in a callback.
     pub fn accept_contact(&mut self, account: AccountId) -> PromiseOrValue<AcceptContactResponse> {
-        self.require_owner_only();
+        self.require_permission(Permission::ManageContacts);
 
         let current_status = self
             .accounts
@@ -299,7 +1015,7 @@ impl MessengerContract {
         let current_status = self.accounts.get(&sender).unwrap_or(AccountStatus::Unknown);
         match current_status {
             AccountStatus::SentPendingRequest => {
-                self.accounts.insert(&sender, &AccountStatus::Contact);
+                self.insert_contact(&sender);
                 self.pending_contacts.remove(&sender);
 
                 let this = env::current_account_id();
@@ -315,6 +1031,156 @@ impl MessengerContract {
         }
     }
 
+    /// `decline_contact` flow:
+    /// 1. Pre-requisite: the target account sent us a contact request via `add_contact`.
+    /// 2. Drop the request, and call `ext_decline_contact` in the other account so it drops
+    ///    the request too. The storage the request used here is refunded along with the call.
+    pub fn decline_contact(&mut self, account: AccountId) -> Promise {
+        self.require_permission(Permission::ManageContacts);
+
+        require!(
+            matches!(
+                self.accounts.get(&account),
+                Some(AccountStatus::ReceivedPendingRequest)
+            ),
+            "There is no pending request from this account"
+        );
+        let initial_storage = env::storage_usage();
+        self.accounts.remove(&account);
+        self.pending_contacts.remove(&account);
+        self.remove_message_request(&account, false);
+        let freed = initial_storage.saturating_sub(env::storage_usage());
+
+        let this = env::current_account_id();
+        Event::contact_request_declined(&account, &this).emit();
+
+        Self::ext(account)
+            .with_attached_deposit((freed as Balance) * env::STORAGE_PRICE_PER_BYTE)
+            .ext_decline_contact()
+            .then(Self::ext(this).decline_contact_callback())
+    }
+
+    /// Part of the `decline_contact` flow. This method is called by another Messenger contract
+    /// when it declines our request to become contacts.
+    #[payable]
+    pub fn ext_decline_contact(&mut self) -> DeclineContactResponse {
+        let receiver = env::predecessor_account_id();
+        if !matches!(
+            self.accounts.get(&receiver),
+            Some(AccountStatus::SentPendingRequest)
+        ) {
+            refund_deposit(None);
+            return DeclineContactResponse::UnknownRequest;
+        }
+
+        self.accounts.remove(&receiver);
+
+        let this = env::current_account_id();
+        Event::contact_request_declined(&this, &receiver).emit();
+
+        DeclineContactResponse::Declined
+    }
+
+    /// `cancel_contact_request` flow:
+    /// 1. Pre-requisite: we sent the target account a contact request via `add_contact`.
+    /// 2. Drop the request, and call `ext_cancel_contact_request` in the other account so it
+    ///    drops the request too. It refunds the storage the request used there.
+    pub fn cancel_contact_request(&mut self, account: AccountId) -> Promise {
+        self.require_permission(Permission::ManageContacts);
+
+        require!(
+            matches!(
+                self.accounts.get(&account),
+                Some(AccountStatus::SentPendingRequest)
+            ),
+            "There is no pending request to this account"
+        );
+        self.accounts.remove(&account);
+
+        let this = env::current_account_id();
+        Event::contact_request_cancelled(&this, &account).emit();
+
+        Self::ext(account)
+            .ext_cancel_contact_request()
+            .then(Self::ext(this).cancel_contact_request_callback())
+    }
+
+    /// Part of the `cancel_contact_request` flow. This method is called by another Messenger
+    /// contract when it cancels its request to become contacts.
+    pub fn ext_cancel_contact_request(&mut self) -> CancelContactResponse {
+        let sender = env::predecessor_account_id();
+        if !matches!(
+            self.accounts.get(&sender),
+            Some(AccountStatus::ReceivedPendingRequest)
+        ) {
+            return CancelContactResponse::UnknownRequest;
+        }
+
+        let initial_storage = env::storage_usage();
+        self.accounts.remove(&sender);
+        self.pending_contacts.remove(&sender);
+        self.remove_message_request(&sender, false);
+
+        let this = env::current_account_id();
+        Event::contact_request_cancelled(&sender, &this).emit();
+
+        // Refund the storage which is freed.
+        let freed = initial_storage.saturating_sub(env::storage_usage());
+        let refund = (freed as Balance) * env::STORAGE_PRICE_PER_BYTE;
+        if refund > 0 {
+            Promise::new(sender).transfer(refund);
+        }
+
+        CancelContactResponse::Cancelled
+    }
+
+    /// `remove_contact` flow:
+    /// 1. Stop treating the account as a contact. The message history with it is kept,
+    ///    unless `purge_history` is set. Up to `DEFAULT_PURGE_SIZE` messages are deleted
+    ///    then, and the rest of them can be deleted with `delete_thread`.
+    /// 2. Call `ext_remove_contact` in the other account, so it drops us as a contact too.
+    /// To talk again, either side must go through the `add_contact` flow.
+    pub fn remove_contact(&mut self, account: AccountId, purge_history: Option<bool>) -> Promise {
+        self.require_permission(Permission::ManageContacts);
+
+        require!(
+            matches!(self.accounts.get(&account), Some(AccountStatus::Contact)),
+            "This account is not a contact"
+        );
+        self.accounts.remove(&account);
+        self.contacts.remove(&account);
+        self.message_windows.remove(&account);
+        if purge_history.unwrap_or(false) {
+            self.purge_history(&account, None, DEFAULT_PURGE_SIZE);
+        }
+
+        let this = env::current_account_id();
+        Event::contact_removed(&this, &account).emit();
+
+        Self::ext(account)
+            .ext_remove_contact()
+            .then(Self::ext(this).remove_contact_callback())
+    }
+
+    /// Part of the `remove_contact` flow. This method is called by another Messenger contract
+    /// when it no longer has us as a contact. The message history with it is kept.
+    /// Nothing is refunded, since either side may have paid for the storage this frees.
+    pub fn ext_remove_contact(&mut self) -> RemoveContactResponse {
+        let sender = env::predecessor_account_id();
+        if !matches!(self.accounts.get(&sender), Some(AccountStatus::Contact)) {
+            return RemoveContactResponse::NotConnected;
+        }
+
+        self.accounts.remove(&sender);
+        self.contacts.remove(&sender);
+        self.message_windows.remove(&sender);
+
+        let this = env::current_account_id();
+        Event::contact_removed(&this, &sender).emit();
+
+        RemoveContactResponse::Removed
+    }
+
     #[private]
     pub fn add_contact_callback(
         &mut self,
@@ -332,7 +1198,7 @@ impl MessengerContract {
                 AddContactResponse::Pending
             }
             Ok(AddContactResponse::Accepted) => {
-                self.accounts.insert(&account, &AccountStatus::Contact);
+                self.insert_contact(&account);
 
                 let this = env::current_account_id();
                 Event::new_contact(&this, &account).emit();
@@ -340,7 +1206,7 @@ impl MessengerContract {
                 AddContactResponse::Accepted
             }
             Ok(AddContactResponse::AlreadyConnected) => {
-                let previous_status = self.accounts.insert(&account, &AccountStatus::Contact);
+                let previous_status = self.insert_contact(&account);
                 if let Some(AccountStatus::Contact) = previous_status {
                     AddContactResponse::AlreadyConnected
                 } else {
@@ -354,6 +1220,111 @@ impl MessengerContract {
         }
     }
 
+    /// The deposit of an auto-reply goes back to the auto-reply budget if it was not delivered.
+    /// The message may have been removed (e.g. it expired) before the response came back,
+    /// in which case only the delivery event is left to record the outcome.
+    #[private]
+    pub fn send_message_callback(
+        &mut self,
+        account: AccountId,
+        message_id: MessageId,
+        auto_reply_deposit: Option<U128>,
+        #[callback_result] response: Result<MessageResponse, PromiseError>,
+    ) -> MessageResponse {
+        let (status, response) = match response {
+            Ok(MessageResponse::Received) => (DeliveryStatus::Delivered, MessageResponse::Received),
+            Ok(other_response) => (
+                DeliveryStatus::Rejected {
+                    reason: other_response,
+                },
+                other_response,
+            ),
+            Err(_e) => (DeliveryStatus::Failed, MessageResponse::InvalidAccount),
+        };
+        if self.sent_messages.get(&message_id).is_some() {
+            self.delivery_statuses.insert(&message_id, &status);
+        }
+        if let Some(deposit) = auto_reply_deposit {
+            if response != MessageResponse::Received {
+                self.auto_reply_budget += deposit.0;
+            }
+        }
+
+        let sender = env::current_account_id();
+        Event::message_delivery(&sender, &account, &message_id, &status).emit();
+
+        response
+    }
+
+    #[private]
+    pub fn edit_message_callback(
+        &mut self,
+        message_id: MessageId,
+        new_content: MessageBody,
+        #[callback_result] response: Result<EditMessageResponse, PromiseError>,
+    ) -> EditMessageResponse {
+        match response {
+            Ok(EditMessageResponse::Edited) => {
+                if let Some(mut sent_message) = self.sent_messages.get(&message_id) {
+                    sent_message.content = new_content;
+                    sent_message.edited = true;
+                    self.sent_messages.insert(&message_id, &sent_message);
+                }
+                EditMessageResponse::Edited
+            }
+            Ok(other_response) => other_response,
+            Err(_e) => EditMessageResponse::InvalidAccount,
+        }
+    }
+
+    #[private]
+    pub fn retract_message_callback(
+        &mut self,
+        message_id: MessageId,
+        #[callback_result] response: Result<RetractMessageResponse, PromiseError>,
+    ) -> RetractMessageResponse {
+        match response {
+            Ok(RetractMessageResponse::Retracted) => {
+                if let Some(sent_message) = self.sent_messages.remove(&message_id) {
+                    self.remove_message_attachments(&message_id);
+                    let tombstone = Tombstone {
+                        parent_id: sent_message.parent_id,
+                        timestamp: sent_message.timestamp,
+                        removed_at: U64(env::block_timestamp()),
+                    };
+                    self.sent_tombstones.insert(&message_id, &tombstone);
+                }
+                RetractMessageResponse::Retracted
+            }
+            Ok(other_response) => other_response,
+            Err(_e) => RetractMessageResponse::InvalidAccount,
+        }
+    }
+
+    #[private]
+    pub fn decline_contact_callback(
+        &mut self,
+        #[callback_result] response: Result<DeclineContactResponse, PromiseError>,
+    ) -> DeclineContactResponse {
+        response.unwrap_or(DeclineContactResponse::InvalidAccount)
+    }
+
+    #[private]
+    pub fn cancel_contact_request_callback(
+        &mut self,
+        #[callback_result] response: Result<CancelContactResponse, PromiseError>,
+    ) -> CancelContactResponse {
+        response.unwrap_or(CancelContactResponse::InvalidAccount)
+    }
+
+    #[private]
+    pub fn remove_contact_callback(
+        &mut self,
+        #[callback_result] response: Result<RemoveContactResponse, PromiseError>,
+    ) -> RemoveContactResponse {
+        response.unwrap_or(RemoveContactResponse::InvalidAccount)
+    }
+
     #[private]
     pub fn accept_contact_callback(
         &mut self,
@@ -362,8 +1333,9 @@ impl MessengerContract {
     ) -> AcceptContactResponse {
         match response {
             Ok(AcceptContactResponse::Accepted) => {
-                self.accounts.insert(&account, &AccountStatus::Contact);
+                self.insert_contact(&account);
                 self.pending_contacts.remove(&account);
+                self.accept_message_request(&account);
 
                 let this = env::current_account_id();
                 Event::new_contact(&this, &account).emit();
@@ -386,13 +1358,310 @@ impl MessengerContract {
         predecessor_account
     }
 
+    /// Send a message with the given deposit, which was paid by `payer`. Auto-replies are
+    /// paid from the auto-reply budget instead, and have no payer.
+    fn send_message_internal(
+        &mut self,
+        account: AccountId,
+        message: MessageBody,
+        reply_to: Option<MessageId>,
+        ttl: Option<U64>,
+        deposit: Balance,
+        payer: Option<AccountId>,
+    ) -> Promise {
+        let is_auto_reply = payer.is_none();
+        let sender = env::current_account_id();
+        let sent_message = SentMessage {
+            recipient: account.clone(),
+            timestamp: U64(env::block_timestamp()),
+            content: message.clone(),
+            parent_id: self.last_sent_message.get(&account),
+            edited: false,
+            reply_to,
+            reactions: Vec::new(),
+        };
+        let message_id = sent_message.id();
+        self.sent_messages.insert(&message_id, &sent_message);
+        self.last_sent_message.insert(&account, &message_id);
+        if let Some(reply_to) = &reply_to {
+            self.add_reply(reply_to, message_id);
+        }
+        let expires_at = ttl.map(|ttl| U64(sent_message.timestamp.0.saturating_add(ttl.0)));
+        self.set_message_expiry(&message_id, expires_at, None);
+        self.delivery_statuses
+            .insert(&message_id, &DeliveryStatus::Pending);
+        Event::message_sent(&sender, &account, &message_id, message.kind()).emit();
+
+        Self::ext(account.clone())
+            .with_attached_deposit(deposit)
+            .receive_message(
+                message,
+                Some(message_id),
+                Some(is_auto_reply),
+                reply_to,
+                expires_at,
+                payer,
+            )
+            .then(Self::ext(sender).send_message_callback(
+                account,
+                message_id,
+                is_auto_reply.then_some(U128(deposit)),
+            ))
+    }
+
+    fn try_auto_reply(&mut self, account: AccountId) {
+        let auto_reply = match &self.auto_reply {
+            Some(auto_reply) => auto_reply,
+            None => return,
+        };
+        let now = env::block_timestamp();
+        if !auto_reply.is_active(now) {
+            return;
+        }
+        if auto_reply.first_message_only {
+            let already_replied = self
+                .last_auto_reply
+                .get(&account)
+                .map(|t| t >= self.auto_reply_set_at)
+                .unwrap_or(false);
+            if already_replied {
+                return;
+            }
+        }
+        let message = MessageBody::Text(auto_reply.message.clone());
+        let deposit = compute_required_message_deposit(&message);
+        if self.auto_reply_budget < deposit {
+            return;
+        }
+        self.auto_reply_budget -= deposit;
+        self.last_auto_reply.insert(&account, &now);
+
+        self.send_message_internal(account, message, None, None, deposit, None);
+    }
+
+    /// Encrypted content must be for one of the keys we published.
+    fn is_known_encryption_key(&self, body: &MessageBody) -> bool {
+        match body {
+            MessageBody::Encrypted(payload) => (payload.key_id as u64) < self.encryption_keys.len(),
+            MessageBody::Text(_)
+            | MessageBody::Markdown(_)
+            | MessageBody::Link { .. }
+            | MessageBody::Attachment(_) => true,
+        }
+    }
+
+    /// The message received before the given one, if the given message (or its tombstone) is
+    /// part of the thread of messages from `sender`.
+    fn received_parent_id(&self, id: &MessageId, sender: &AccountId) -> Option<Option<MessageId>> {
+        if let Some(message) = self.received_message(id) {
+            return (&message.sender == sender).then_some(message.parent_id);
+        }
+        let parent_id = self.tombstones.get(id)?.parent_id;
+        // Tombstones do not record the sender, so the thread is the one of the
+        // closest older message which is still stored.
+        let mut next_id = parent_id;
+        while let Some(current_id) = next_id {
+            if let Some(message) = self.received_message(&current_id) {
+                return (&message.sender == sender).then_some(parent_id);
+            }
+            next_id = self.tombstones.get(&current_id)?.parent_id;
+        }
+        Some(parent_id)
+    }
+
+    /// The message sent before the given one, if the given message (or its tombstone) is
+    /// part of the thread of messages to `recipient`.
+    fn sent_parent_id(&self, id: &MessageId, recipient: &AccountId) -> Option<Option<MessageId>> {
+        if let Some(message) = self.sent_messages.get(id) {
+            return (&message.recipient == recipient).then_some(message.parent_id);
+        }
+        let parent_id = self.sent_tombstones.get(id)?.parent_id;
+        let mut next_id = parent_id;
+        while let Some(current_id) = next_id {
+            if let Some(message) = self.sent_messages.get(&current_id) {
+                return (&message.recipient == recipient).then_some(parent_id);
+            }
+            next_id = self.sent_tombstones.get(&current_id)?.parent_id;
+        }
+        Some(parent_id)
+    }
+
+    /// Whether a message we sent or received, or a tombstone, is stored under `id`.
+    pub(crate) fn is_known_message_id(&self, id: &MessageId) -> bool {
+        self.received_message(id).is_some()
+            || self.message_request_ids.contains(id)
+            || self.tombstones.get(id).is_some()
+            || self.sent_messages.get(id).is_some()
+            || self.sent_tombstones.get(id).is_some()
+    }
+
+    /// Whether the message was sent to us by `account`, or sent by us to `account`.
+    fn is_message_with(&self, account: &AccountId, message_id: &MessageId) -> bool {
+        let received = self
+            .messages
+            .get(message_id)
+            .map(|m| &m.sender == account)
+            .unwrap_or(false);
+        received
+            || self
+                .sent_messages
+                .get(message_id)
+                .map(|m| &m.recipient == account)
+                .unwrap_or(false)
+    }
+
+    /// Replace a message we received with a tombstone, along with everything attached to it.
+    /// Returns the storage freed which the sender of the message paid for: the message itself,
+    /// its revisions and its expiry, net of the tombstone. The rest (e.g. reactions and
+    /// replies) was paid for by other accounts.
+    fn replace_with_tombstone(
+        &mut self,
+        id: &MessageId,
+        message: Message,
+        removed_at: u64,
+    ) -> StorageUsage {
+        let initial_storage = env::storage_usage();
+        self.remove_received_message(id);
+        self.unread_messages.remove(id);
+        self.read_messages.remove(id);
+        self.message_revisions.remove(id);
+        self.remove_message_expiry(id);
+        let tombstone = Tombstone {
+            parent_id: message.parent_id,
+            timestamp: message.timestamp,
+            removed_at: U64(removed_at),
+        };
+        self.tombstones.insert(id, &tombstone);
+        let freed = initial_storage.saturating_sub(env::storage_usage());
+        self.remove_message_attachments(id);
+        freed
+    }
+
+    /// Remove the data kept about a message, sent or received, apart from the message itself.
+    fn remove_message_attachments(&mut self, id: &MessageId) {
+        self.message_revisions.remove(id);
+        self.reactions.remove(id);
+        self.replies.remove(id);
+        self.remove_message_expiry(id);
+        self.delivery_statuses.remove(id);
+        self.read_receipts.remove(id);
+    }
+
+    fn add_reply(&mut self, reply_to: &MessageId, message_id: MessageId) {
+        let mut replies = self.replies.get(reply_to).unwrap_or_default();
+        replies.push(message_id);
+        self.replies.insert(reply_to, &replies);
+    }
+
+    /// Look up a message we either sent or received, unless it expired.
+    fn find_message(&self, id: MessageId, this: &AccountId) -> Option<MessageWithId> {
+        if self.is_expired(&id) {
+            return None;
+        }
+        let message = match self.received_message(&id) {
+            Some(message) => message,
+            None => self.sent_messages.get(&id)?.into_message(this.clone()),
+        };
+        Some(MessageWithId {
+            id,
+            message: Message {
+                reactions: self.reactions_of(&id),
+                ..message
+            },
+        })
+    }
+
     fn get_message(&self, id: &MessageId) -> Message {
-        self.messages
+        self.received_message(id)
+            .unwrap_or_else(|| env::panic_str("Missing message"))
+    }
+
+    /// Follow a chain of received messages, starting from `id`, to the first message that
+    /// has not been removed or expired.
+    fn next_received_message(&self, mut id: Option<MessageId>) -> Option<MessageWithId> {
+        while let Some(current_id) = id {
+            if let Some(mut message) = self.received_message(&current_id) {
+                if self.is_expired(&current_id) {
+                    id = message.parent_id;
+                    continue;
+                }
+                message.reactions = self.reactions_of(&current_id);
+                return Some(MessageWithId {
+                    id: current_id,
+                    message,
+                });
+            }
+            id = self.get_tombstone(&self.tombstones, &current_id).parent_id;
+        }
+        None
+    }
+
+    /// Follow a chain of sent messages, starting from `id`, to the first message that
+    /// has not been removed or expired.
+    fn next_sent_message(
+        &self,
+        mut id: Option<MessageId>,
+        this: &AccountId,
+    ) -> Option<MessageWithId> {
+        while let Some(current_id) = id {
+            if let Some(mut message) = self.sent_messages.get(&current_id) {
+                if self.is_expired(&current_id) {
+                    id = message.parent_id;
+                    continue;
+                }
+                message.reactions = self.reactions_of(&current_id);
+                return Some(MessageWithId {
+                    id: current_id,
+                    message: message.into_message(this.clone()),
+                });
+            }
+            id = self
+                .get_tombstone(&self.sent_tombstones, &current_id)
+                .parent_id;
+        }
+        None
+    }
+
+    fn get_tombstone(
+        &self,
+        tombstones: &LookupMap<MessageId, Tombstone>,
+        id: &MessageId,
+    ) -> Tombstone {
+        tombstones
             .get(id)
             .unwrap_or_else(|| env::panic_str("Missing message"))
     }
 }
 
-fn compute_required_message_deposit(message: &str) -> Balance {
-    (message.len() as Balance) * env::STORAGE_PRICE_PER_BYTE
+/// Take up to `max_size` items (all of them by default) from a list of `len` items,
+/// starting at `from_index`.
+fn page_from_index<T>(
+    items: impl Iterator<Item = T>,
+    len: u64,
+    from_index: Option<u64>,
+    max_size: Option<usize>,
+) -> Page<T, u64> {
+    let from_index = from_index.unwrap_or(0);
+    let items = items.skip(from_index as usize);
+    let items: Vec<T> = match max_size {
+        Some(size) => items.take(size).collect(),
+        None => items.collect(),
+    };
+    let end = from_index + items.len() as u64;
+    Page {
+        items,
+        next: (end < len).then_some(end),
+    }
+}
+
+/// An upper bound on the storage cost of a message. The receiving contract refunds
+/// whatever it does not use. Encrypted messages pay for the size of their ciphertext.
+fn compute_required_message_deposit(message: &MessageBody) -> Balance {
+    (MESSAGE_STORAGE_OVERHEAD as Balance + message.len() as Balance) * env::STORAGE_PRICE_PER_BYTE
+}
+
+/// The previous content of an edited message is kept as a revision, so editing
+/// requires the same deposit as sending the new content.
+fn compute_required_edit_deposit(new_content: &MessageBody) -> Balance {
+    compute_required_message_deposit(new_content)
 }
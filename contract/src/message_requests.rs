@@ -0,0 +1,243 @@
+//! Message requests. An account which is not our contact can send us a single message along
+//! with its contact request, if it pays the fee set by our `MessageRequestPolicy`. The message
+//! is kept apart from the inbox until the owner handles the request:
+//! - `accept_contact` moves the message into the inbox,
+//! - `decline_contact` drops it,
+//! - `block_account` drops it and always keeps the fee.
+//! Whether the fee is refunded otherwise depends on the policy when the request was received.
+//! Refunds are credited to the storage balance of whoever paid the fee with the sender.
+
+use crate::{
+    compute_required_message_deposit,
+    events::Event,
+    page_from_index,
+    storage::{refund_deposit, refund_to},
+    types::{
+        AccountStatus, DeliveryStatus, Message, MessageBody, MessageId, MessageRequest,
+        MessageRequestPolicy, MessageRequestRefund, MessageResponse, Page, Permission, SentMessage,
+    },
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{
+    env,
+    json_types::{U128, U64},
+    near_bindgen, require, AccountId, Balance, Promise, PromiseError,
+};
+
+#[near_bindgen]
+impl MessengerContract {
+    /// Set the terms under which accounts which are not our contacts can send us a message
+    /// request. Passing `None` stops accepting message requests. Requests already received
+    /// keep the refund policy they were sent under.
+    pub fn set_message_request_policy(&mut self, policy: Option<MessageRequestPolicy>) {
+        self.require_owner_only();
+        self.message_request_policy = policy;
+    }
+
+    pub fn view_message_request_policy(&self) -> Option<MessageRequestPolicy> {
+        self.message_request_policy
+    }
+
+    pub fn view_message_requests(
+        &self,
+        from_index: Option<u64>,
+        max_size: Option<usize>,
+    ) -> Page<MessageRequest, u64> {
+        let requests = self.message_requests.values();
+        page_from_index(requests, self.message_requests.len(), from_index, max_size)
+    }
+
+    /// `send_message_request` flow:
+    /// 1. Record the message in our outbox and call `ext_message_request` in the account we
+    ///    want to reach. The `fee` must match its `view_message_request_policy`.
+    /// 2. In a callback, record the contact request as pending if the message was accepted.
+    /// The fee and the deposit for the recipient come from the attached deposit or the
+    /// caller's storage balance.
+    #[payable]
+    pub fn send_message_request(
+        &mut self,
+        account: AccountId,
+        message: MessageBody,
+        fee: U128,
+    ) -> Promise {
+        let caller = self.require_permission(Permission::ManageContacts);
+
+        require!(
+            matches!(
+                self.accounts
+                    .get(&account)
+                    .unwrap_or(AccountStatus::Unknown),
+                AccountStatus::Unknown
+            ),
+            "Message requests can only be sent to accounts we are not connected with"
+        );
+        let deposit = fee.0 + compute_required_message_deposit(&message);
+        self.pay(&caller, deposit, None);
+
+        let sender = env::current_account_id();
+        let sent_message = SentMessage {
+            recipient: account.clone(),
+            timestamp: U64(env::block_timestamp()),
+            content: message.clone(),
+            parent_id: self.last_sent_message.get(&account),
+            edited: false,
+            reply_to: None,
+            reactions: Vec::new(),
+        };
+        let message_id = sent_message.id();
+        self.sent_messages.insert(&message_id, &sent_message);
+        self.last_sent_message.insert(&account, &message_id);
+        self.delivery_statuses
+            .insert(&message_id, &DeliveryStatus::Pending);
+        Event::message_sent(&sender, &account, &message_id, message.kind()).emit();
+
+        Self::ext(account.clone())
+            .with_attached_deposit(deposit)
+            .ext_message_request(message, message_id, Some(caller))
+            .then(Self::ext(sender).message_request_callback(account, message_id))
+    }
+
+    /// Part of the `send_message_request` flow. This method is called by another Messenger
+    /// contract which is not our contact when it wants to send us a message.
+    #[payable]
+    pub fn ext_message_request(
+        &mut self,
+        content: MessageBody,
+        message_id: MessageId,
+        payer: Option<AccountId>,
+    ) -> MessageResponse {
+        let sender = env::predecessor_account_id();
+        let policy = match self.message_request_policy {
+            Some(policy) => policy,
+            None => {
+                refund_deposit(payer.as_ref());
+                return MessageResponse::NotConnected;
+            }
+        };
+        let status = self.accounts.get(&sender).unwrap_or(AccountStatus::Unknown);
+        let response = match status {
+            AccountStatus::Unknown => None,
+            AccountStatus::Blocked => Some(MessageResponse::Blocked),
+            AccountStatus::Contact => Some(MessageResponse::AlreadyConnected),
+            AccountStatus::SentPendingRequest | AccountStatus::ReceivedPendingRequest => {
+                Some(MessageResponse::PendingRequest)
+            }
+        };
+        if let Some(response) = response {
+            refund_deposit(payer.as_ref());
+            return response;
+        }
+        if self.is_too_large(content.len()) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::TooLarge;
+        }
+        if !self.can_pay(
+            &sender,
+            policy.fee.0 + compute_required_message_deposit(&content),
+        ) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::InsufficientDeposit;
+        }
+        if !self.is_known_encryption_key(&content) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::UnknownEncryptionKey;
+        }
+        if self.is_known_message_id(&message_id) {
+            refund_deposit(payer.as_ref());
+            return MessageResponse::DuplicateMessage;
+        }
+
+        let initial_storage = env::storage_usage();
+        let message = Message {
+            content,
+            sender: sender.clone(),
+            // Set when the request is accepted, since more messages may be received until then.
+            parent_id: None,
+            timestamp: U64(env::block_timestamp()),
+            edited: false,
+            reply_to: None,
+            reactions: Vec::new(),
+        };
+        let message_kind = message.content.kind();
+        let request = MessageRequest {
+            id: message_id,
+            message,
+            fee: policy.fee,
+            refund: policy.refund,
+            payer: payer.clone(),
+        };
+        self.message_requests.insert(&sender, &request);
+        self.message_request_ids.insert(&message_id);
+        self.accounts
+            .insert(&sender, &AccountStatus::ReceivedPendingRequest);
+        self.pending_contacts.insert(&sender);
+        let used = env::storage_usage().saturating_sub(initial_storage);
+        self.pay(
+            &sender,
+            policy.fee.0 + (used as Balance) * env::STORAGE_PRICE_PER_BYTE,
+            payer.as_ref(),
+        );
+
+        let receiver = env::current_account_id();
+        Event::received_contact_request(&sender, &receiver).emit();
+        Event::message_request_received(&sender, &receiver, &message_id, message_kind).emit();
+
+        MessageResponse::Received
+    }
+
+    #[private]
+    pub fn message_request_callback(
+        &mut self,
+        account: AccountId,
+        message_id: MessageId,
+        #[callback_result] response: Result<MessageResponse, PromiseError>,
+    ) -> MessageResponse {
+        if let Ok(MessageResponse::Received) = response {
+            self.accounts
+                .insert(&account, &AccountStatus::SentPendingRequest);
+
+            let sender = env::current_account_id();
+            Event::pending_contact_request(&sender, &account).emit();
+        }
+        self.send_message_callback(account, message_id, None, response)
+    }
+}
+
+impl MessengerContract {
+    /// Move the message request from `account` (if there is one) into the inbox, now that
+    /// `account` is our contact. The message is dropped if a message with the same id was
+    /// received since.
+    pub(crate) fn accept_message_request(&mut self, account: &AccountId) {
+        let request = match self.message_requests.remove(account) {
+            Some(request) => request,
+            None => return,
+        };
+        self.message_request_ids.remove(&request.id);
+        if !self.is_known_message_id(&request.id) {
+            let message = Message {
+                parent_id: self.last_received_message.get(account),
+                ..request.message
+            };
+            self.messages.insert(&request.id, &message);
+            self.unread_messages.insert(&request.id);
+            self.last_received_message.insert(account, &request.id);
+        }
+
+        if request.refund != MessageRequestRefund::Never {
+            refund_to(account.clone(), request.fee.0, request.payer.as_ref());
+        }
+    }
+
+    /// Drop the message request from `account` (if there is one) without accepting it. The
+    /// fee is refunded if the request was sent under the `Always` policy, unless `keep_fee`.
+    pub(crate) fn remove_message_request(&mut self, account: &AccountId, keep_fee: bool) {
+        let request = match self.message_requests.remove(account) {
+            Some(request) => request,
+            None => return,
+        };
+        self.message_request_ids.remove(&request.id);
+        if !keep_fee && request.refund == MessageRequestRefund::Always {
+            refund_to(account.clone(), request.fee.0, request.payer.as_ref());
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Versioning of the contract state, so that contracts deployed with an earlier version of
+//! the code can be upgraded in place.
+//!
+//! The version is stored under its own key, apart from the contract struct, so that it can be
+//! read before knowing the layout of the struct. State written by the first release has no
+//! version and is read as version 0. Changing the layout of `MessengerContract`
+//! requires:
+//! 1. keeping a copy of the old struct in this module,
+//! 2. adding a variant for it to `VersionedState` which converts it into the new layout,
+//! 3. incrementing `CURRENT_STATE_VERSION`.
+//!
+//! The owner can deploy new code with `upgrade`, which migrates the state in the same step.
+//! Migrating must not go through collections, which can be too large to do in one call:
+//! - messages received by the first release keep its layout under their old prefix, and are
+//!   converted when they are read, and stored in the current layout once they are changed,
+//! - contacts made by the first release get their metadata once the owner sets it or lists
+//!   them with `backfill_contacts`, since the `accounts` map can not be gone through.
+
+use crate::{
+    types::{AccountStatus, Message, MessageBody, MessageId, SpamPolicy},
+    MessengerContract, MessengerContractExt, StoragePrefix,
+};
+use near_sdk::{
+    borsh::{self, BorshDeserialize, BorshSerialize},
+    collections::{LookupMap, LookupSet, TreeMap, UnorderedMap, UnorderedSet, Vector},
+    env,
+    json_types::U64,
+    near_bindgen, AccountId, Gas, GasWeight, IntoStorageKey, Promise,
+};
+
+pub type StateVersion = u32;
+
+/// The version of the layout of the state this code uses.
+pub const CURRENT_STATE_VERSION: StateVersion = 1;
+
+/// The contract state in any of the layouts it was stored with.
+enum VersionedState {
+    V0(OldStateV0),
+    V1(MessengerContract),
+}
+
+impl VersionedState {
+    fn read(version: StateVersion) -> Self {
+        match version {
+            0 => Self::V0(read_contract_state()),
+            1 => Self::V1(read_contract_state()),
+            _ => env::panic_str("Unknown state version"),
+        }
+    }
+
+    fn into_current(self) -> MessengerContract {
+        match self {
+            Self::V0(state) => state.into(),
+            Self::V1(state) => state,
+        }
+    }
+}
+
+/// Layout of the first release, before the state was versioned.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldStateV0 {
+    accounts: LookupMap<AccountId, AccountStatus>,
+    messages: LookupMap<MessageId, MessageV0>,
+    unread_messages: UnorderedSet<MessageId>,
+    read_messages: UnorderedSet<MessageId>,
+    last_received_message: LookupMap<AccountId, MessageId>,
+    pending_contacts: UnorderedSet<AccountId>,
+    owner: AccountId,
+}
+
+/// Layout of `Message` in the first release, when the content could only be text.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct MessageV0 {
+    sender: AccountId,
+    timestamp: U64,
+    content: String,
+    parent_id: Option<MessageId>,
+}
+
+impl From<MessageV0> for Message {
+    fn from(message: MessageV0) -> Self {
+        Self {
+            sender: message.sender,
+            timestamp: message.timestamp,
+            content: MessageBody::Text(message.content),
+            parent_id: message.parent_id,
+            edited: false,
+            reply_to: None,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+impl From<OldStateV0> for MessengerContract {
+    fn from(state: OldStateV0) -> Self {
+        Self {
+            accounts: state.accounts,
+            messages: LookupMap::new(StoragePrefix::ReceivedMessages),
+            legacy_messages: state.messages,
+            unread_messages: state.unread_messages,
+            read_messages: state.read_messages,
+            last_received_message: state.last_received_message,
+            pending_contacts: state.pending_contacts,
+            sent_messages: LookupMap::new(StoragePrefix::SentMessages),
+            last_sent_message: LookupMap::new(StoragePrefix::LastSentMessage),
+            delivery_statuses: LookupMap::new(StoragePrefix::DeliveryStatuses),
+            auto_reply: None,
+            auto_reply_set_at: 0,
+            auto_reply_budget: 0,
+            last_auto_reply: LookupMap::new(StoragePrefix::LastAutoReply),
+            groups: UnorderedMap::new(StoragePrefix::Groups),
+            group_messages: LookupMap::new(StoragePrefix::GroupMessages),
+            last_group_message: LookupMap::new(StoragePrefix::LastGroupMessage),
+            message_revisions: LookupMap::new(StoragePrefix::MessageRevisions),
+            tombstones: LookupMap::new(StoragePrefix::Tombstones),
+            sent_tombstones: LookupMap::new(StoragePrefix::SentTombstones),
+            read_receipts: LookupMap::new(StoragePrefix::ReadReceipts),
+            read_receipts_enabled: true,
+            read_receipts_disabled: LookupSet::new(StoragePrefix::ReadReceiptsDisabled),
+            storage_balances: LookupMap::new(StoragePrefix::StorageBalances),
+            encryption_keys: Vector::new(StoragePrefix::EncryptionKeys),
+            reactions: LookupMap::new(StoragePrefix::Reactions),
+            replies: LookupMap::new(StoragePrefix::Replies),
+            delegates: UnorderedMap::new(StoragePrefix::Delegates),
+            message_request_policy: None,
+            message_requests: UnorderedMap::new(StoragePrefix::MessageRequests),
+            message_request_ids: LookupSet::new(StoragePrefix::MessageRequestIds),
+            spam_policy: SpamPolicy::default(),
+            message_windows: LookupMap::new(StoragePrefix::MessageWindows),
+            message_expiries: UnorderedMap::new(StoragePrefix::MessageExpiries),
+            contacts: UnorderedMap::new(StoragePrefix::Contacts),
+            expiry_queue: TreeMap::new(StoragePrefix::ExpiryQueue),
+            expiry_payers: LookupMap::new(StoragePrefix::ExpiryPayers),
+            owner: state.owner,
+            proposed_owner: None,
+        }
+    }
+}
+
+#[near_bindgen]
+impl MessengerContract {
+    /// Upgrade the state written by an earlier version of the contract to the current layout.
+    /// This must be called right after deploying new code to a contract which already has state.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = VersionedState::read(view_state_version_internal()).into_current();
+        write_state_version(CURRENT_STATE_VERSION);
+        state
+    }
+
+    /// Deploy new code to this contract and migrate the state to it. The code is given as the
+    /// raw input of the call (not JSON). Deploying and migrating are a single batch of actions,
+    /// so if the migration fails then the old code is kept as well.
+    pub fn upgrade(&self) -> Promise {
+        self.require_owner_only();
+        let code = env::input().unwrap_or_else(|| env::panic_str("Missing contract code"));
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call_weight(
+                "migrate".into(),
+                Vec::new(),
+                0,
+                Gas(0),
+                GasWeight::default(),
+            )
+    }
+
+    /// The version of the layout of the stored state. If this is different from the version
+    /// of the deployed code, then `migrate` must be called before the contract can be used.
+    /// This does not read the state itself, so it also works before migrating.
+    pub fn view_state_version() -> StateVersion {
+        view_state_version_internal()
+    }
+}
+
+impl MessengerContract {
+    /// A message we received, whether it is stored in the current layout or in the one of
+    /// the first release.
+    pub(crate) fn received_message(&self, id: &MessageId) -> Option<Message> {
+        self.messages
+            .get(id)
+            .or_else(|| self.legacy_messages.get(id).map(Message::from))
+    }
+
+    /// Store a message we received in the current layout, replacing any copy in the layout
+    /// of the first release.
+    pub(crate) fn insert_received_message(&mut self, id: &MessageId, message: &Message) {
+        self.messages.insert(id, message);
+        self.legacy_messages.remove(id);
+    }
+
+    pub(crate) fn remove_received_message(&mut self, id: &MessageId) {
+        self.messages.remove(id);
+        self.legacy_messages.remove(id);
+    }
+}
+
+/// State written before versioning was added has the layout of the first release.
+fn view_state_version_internal() -> StateVersion {
+    env::storage_read(&state_version_key())
+        .map(|bytes| {
+            StateVersion::try_from_slice(&bytes)
+                .unwrap_or_else(|_e| env::panic_str("Failed to read state version"))
+        })
+        .unwrap_or(0)
+}
+
+pub(crate) fn write_state_version(version: StateVersion) {
+    let bytes = version
+        .try_to_vec()
+        .unwrap_or_else(|_e| env::panic_str("Failed to serialize state version"));
+    env::storage_write(&state_version_key(), &bytes);
+}
+
+fn state_version_key() -> Vec<u8> {
+    StoragePrefix::StateVersion.into_storage_key()
+}
+
+fn read_contract_state<T: BorshDeserialize>() -> T {
+    env::state_read().unwrap_or_else(|| env::panic_str("The contract has no state to migrate"))
+}
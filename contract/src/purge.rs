@@ -0,0 +1,329 @@
+//! Deleting messages, so that the storage they use no longer locks up the balance of the
+//! contract account. The messages of a thread are chained through their `parent_id`, from
+//! the most recent one back to the first. When messages (or tombstones) are deleted from
+//! the middle of a chain, the message after them is linked to the one before them, so that
+//! `view_thread` and `view_conversation` still see the rest of the thread.
+//! Unlike a retracted message, a deleted message leaves no tombstone behind.
+
+use crate::{
+    types::{Message, MessageId, PurgeCursor, PurgeResult, SentMessage},
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{env, json_types::U64, near_bindgen, AccountId};
+
+/// Number of messages the purge methods go through by default.
+pub(crate) const DEFAULT_PURGE_SIZE: usize = 100;
+
+#[near_bindgen]
+impl MessengerContract {
+    /// Delete the messages we read which were received before `before` (in nanoseconds since
+    /// the Unix epoch). Only the messages from `account` are deleted if it is given, and
+    /// otherwise the whole inbox is gone through. At most `max_size` messages are gone
+    /// through, so that a long history can be purged over several calls by passing the
+    /// returned `next` cursor as `from`.
+    pub fn purge_read_messages(
+        &mut self,
+        account: Option<AccountId>,
+        before: U64,
+        max_size: Option<usize>,
+        from: Option<PurgeCursor>,
+    ) -> PurgeResult {
+        self.require_owner_only();
+
+        let initial_storage = env::storage_usage();
+        let mut remaining = max_size.unwrap_or(DEFAULT_PURGE_SIZE);
+        let should_remove = |this: &Self, id: &MessageId, message: Option<&Message>| {
+            let is_old = message.map(|m| m.timestamp.0 < before.0).unwrap_or(false);
+            is_old && this.read_messages.contains(id)
+        };
+        let next = match account {
+            Some(account) => {
+                let kept = from.and_then(|cursor| cursor.kept);
+                self.prune_received_thread(&account, kept, &mut remaining, should_remove)
+            }
+            None => self.prune_inbox(from.unwrap_or_default(), &mut remaining, should_remove),
+        };
+        PurgeResult {
+            freed: U64(initial_storage.saturating_sub(env::storage_usage())),
+            next,
+        }
+    }
+
+    /// Delete every message we exchanged with `account`, both sent and received, whether
+    /// or not it is still our contact. At most `max_size` messages are gone through, as
+    /// for `purge_read_messages`.
+    pub fn delete_thread(
+        &mut self,
+        account: AccountId,
+        max_size: Option<usize>,
+        from: Option<PurgeCursor>,
+    ) -> PurgeResult {
+        self.require_owner_only();
+
+        let initial_storage = env::storage_usage();
+        let next = self.purge_history(&account, from, max_size.unwrap_or(DEFAULT_PURGE_SIZE));
+        PurgeResult {
+            freed: U64(initial_storage.saturating_sub(env::storage_usage())),
+            next,
+        }
+    }
+
+    /// Delete the tombstones of the messages removed from the conversation with `account`.
+    /// At most `max_size` messages are gone through, as for `purge_read_messages`.
+    pub fn clear_tombstones(
+        &mut self,
+        account: AccountId,
+        max_size: Option<usize>,
+        from: Option<PurgeCursor>,
+    ) -> PurgeResult {
+        self.require_owner_only();
+
+        let initial_storage = env::storage_usage();
+        let next = self.prune_conversation(
+            &account,
+            from,
+            max_size.unwrap_or(DEFAULT_PURGE_SIZE),
+            |_, _, message| message.is_none(),
+            |_, _, message| message.is_none(),
+        );
+        PurgeResult {
+            freed: U64(initial_storage.saturating_sub(env::storage_usage())),
+            next,
+        }
+    }
+}
+
+impl MessengerContract {
+    /// Remove the messages we exchanged with `account`, along with everything attached to
+    /// those messages (revisions, reactions, tombstones, ...). At most `max_size` messages
+    /// are gone through; returns the cursor to continue from if there are more.
+    pub(crate) fn purge_history(
+        &mut self,
+        account: &AccountId,
+        from: Option<PurgeCursor>,
+        max_size: usize,
+    ) -> Option<PurgeCursor> {
+        self.prune_conversation(account, from, max_size, |_, _, _| true, |_, _, _| true)
+    }
+
+    /// Go through the messages received from `account`, then the ones sent to it, deleting
+    /// those for which `remove_received` or `remove_sent` is true.
+    fn prune_conversation(
+        &mut self,
+        account: &AccountId,
+        from: Option<PurgeCursor>,
+        max_size: usize,
+        remove_received: impl Fn(&Self, &MessageId, Option<&Message>) -> bool,
+        remove_sent: impl Fn(&Self, &MessageId, Option<&SentMessage>) -> bool,
+    ) -> Option<PurgeCursor> {
+        let mut remaining = max_size;
+        let mut cursor = from.unwrap_or_default();
+        if !cursor.sent {
+            let next = self.prune_received_thread(
+                account,
+                cursor.kept.take(),
+                &mut remaining,
+                remove_received,
+            );
+            if next.is_some() {
+                return next;
+            }
+        }
+        let kept = if cursor.sent { cursor.kept } else { None };
+        self.prune_sent_thread(account, kept, &mut remaining, remove_sent)
+            .map(|next| PurgeCursor { sent: true, ..next })
+    }
+
+    /// Delete the messages of the inbox for which `should_remove` is true. The read messages
+    /// are gone through in the order they are stored in, and for each message to delete,
+    /// the whole thread of its sender is pruned so that the thread stays linked.
+    fn prune_inbox(
+        &mut self,
+        mut cursor: PurgeCursor,
+        remaining: &mut usize,
+        should_remove: impl Fn(&Self, &MessageId, Option<&Message>) -> bool,
+    ) -> Option<PurgeCursor> {
+        loop {
+            if let Some(account) = cursor.account.take() {
+                let next = self.prune_received_thread(
+                    &account,
+                    cursor.kept.take(),
+                    remaining,
+                    &should_remove,
+                );
+                if let Some(next) = next {
+                    return Some(PurgeCursor {
+                        account: Some(account),
+                        index: cursor.index,
+                        ..next
+                    });
+                }
+            }
+            // Deleting a message moves the last read message into its place, so the messages
+            // before `index` are all kept.
+            loop {
+                let id = self.read_messages.as_vector().get(cursor.index)?;
+                if *remaining == 0 {
+                    return Some(cursor);
+                }
+                *remaining -= 1;
+                let message = self.received_message(&id);
+                if should_remove(&*self, &id, message.as_ref()) {
+                    cursor.account = message.map(|m| m.sender);
+                    break;
+                }
+                cursor.index += 1;
+            }
+        }
+    }
+
+    /// Delete the messages received from `account` for which `should_remove` is true, along
+    /// with everything attached to them. `should_remove` is given `None` for tombstones.
+    /// The thread is gone through from the message before `kept` (or from the most recent
+    /// one), until `remaining` messages have been gone through.
+    /// Returns the cursor to continue from, unless the whole thread has been gone through.
+    fn prune_received_thread(
+        &mut self,
+        account: &AccountId,
+        mut kept: Option<MessageId>,
+        remaining: &mut usize,
+        should_remove: impl Fn(&Self, &MessageId, Option<&Message>) -> bool,
+    ) -> Option<PurgeCursor> {
+        let mut id = match &kept {
+            Some(kept) => self
+                .received_parent_id(kept, account)
+                .unwrap_or_else(|| env::panic_str("Unknown cursor")),
+            None => self.last_received_message.get(account),
+        };
+        while let Some(current_id) = id {
+            if *remaining == 0 {
+                return Some(PurgeCursor {
+                    kept,
+                    ..Default::default()
+                });
+            }
+            *remaining -= 1;
+            let message = self.received_message(&current_id);
+            id = match &message {
+                Some(message) => message.parent_id,
+                None => self.get_tombstone(&self.tombstones, &current_id).parent_id,
+            };
+            if !should_remove(&*self, &current_id, message.as_ref()) {
+                kept = Some(current_id);
+                continue;
+            }
+            self.remove_received_message(&current_id);
+            self.tombstones.remove(&current_id);
+            self.unread_messages.remove(&current_id);
+            self.read_messages.remove(&current_id);
+            self.remove_message_attachments(&current_id);
+            self.set_received_parent_id(account, kept.as_ref(), id);
+        }
+        None
+    }
+
+    /// Delete the messages sent to `account` for which `should_remove` is true, along with
+    /// everything attached to them, in the same way as `prune_received_thread`.
+    fn prune_sent_thread(
+        &mut self,
+        account: &AccountId,
+        mut kept: Option<MessageId>,
+        remaining: &mut usize,
+        should_remove: impl Fn(&Self, &MessageId, Option<&SentMessage>) -> bool,
+    ) -> Option<PurgeCursor> {
+        let mut id = match &kept {
+            Some(kept) => self
+                .sent_parent_id(kept, account)
+                .unwrap_or_else(|| env::panic_str("Unknown cursor")),
+            None => self.last_sent_message.get(account),
+        };
+        while let Some(current_id) = id {
+            if *remaining == 0 {
+                return Some(PurgeCursor {
+                    kept,
+                    ..Default::default()
+                });
+            }
+            *remaining -= 1;
+            let message = self.sent_messages.get(&current_id);
+            id = match &message {
+                Some(message) => message.parent_id,
+                None => {
+                    self.get_tombstone(&self.sent_tombstones, &current_id)
+                        .parent_id
+                }
+            };
+            if !should_remove(&*self, &current_id, message.as_ref()) {
+                kept = Some(current_id);
+                continue;
+            }
+            self.sent_messages.remove(&current_id);
+            self.sent_tombstones.remove(&current_id);
+            self.remove_message_attachments(&current_id);
+            self.set_sent_parent_id(account, kept.as_ref(), id);
+        }
+        None
+    }
+
+    /// Link the received message or tombstone `child` to `parent_id`. Without a `child`,
+    /// `parent_id` becomes the most recent message received from `account`.
+    fn set_received_parent_id(
+        &mut self,
+        account: &AccountId,
+        child: Option<&MessageId>,
+        parent_id: Option<MessageId>,
+    ) {
+        let child = match child {
+            Some(child) => child,
+            None => {
+                match parent_id {
+                    Some(parent_id) => self.last_received_message.insert(account, &parent_id),
+                    None => self.last_received_message.remove(account),
+                };
+                return;
+            }
+        };
+        match self.received_message(child) {
+            Some(mut message) => {
+                message.parent_id = parent_id;
+                self.insert_received_message(child, &message);
+            }
+            None => {
+                let mut tombstone = self.get_tombstone(&self.tombstones, child);
+                tombstone.parent_id = parent_id;
+                self.tombstones.insert(child, &tombstone);
+            }
+        }
+    }
+
+    /// Link the sent message or tombstone `child` to `parent_id`. Without a `child`,
+    /// `parent_id` becomes the most recent message sent to `account`.
+    fn set_sent_parent_id(
+        &mut self,
+        account: &AccountId,
+        child: Option<&MessageId>,
+        parent_id: Option<MessageId>,
+    ) {
+        let child = match child {
+            Some(child) => child,
+            None => {
+                match parent_id {
+                    Some(parent_id) => self.last_sent_message.insert(account, &parent_id),
+                    None => self.last_sent_message.remove(account),
+                };
+                return;
+            }
+        };
+        match self.sent_messages.get(child) {
+            Some(mut message) => {
+                message.parent_id = parent_id;
+                self.sent_messages.insert(child, &message);
+            }
+            None => {
+                let mut tombstone = self.get_tombstone(&self.sent_tombstones, child);
+                tombstone.parent_id = parent_id;
+                self.sent_tombstones.insert(child, &tombstone);
+            }
+        }
+    }
+}
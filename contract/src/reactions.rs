@@ -0,0 +1,159 @@
+//! Reactions to messages. Either participant of a conversation can react to any message
+//! in it, whoever sent it. The reaction is stored by both contracts, so that it is shown
+//! alongside the message on both sides of the conversation.
+
+use crate::{
+    events::Event,
+    storage::refund_deposit,
+    types::{AccountStatus, MessageId, Permission, ReactResponse, Reaction},
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{
+    env, json_types::U64, near_bindgen, require, AccountId, Balance, Promise, PromiseError,
+    StorageUsage,
+};
+
+/// Reactions are meant to be a single emoji, which can take several code points.
+const MAX_REACTION_LENGTH: usize = 32;
+
+/// Number of different reactions each participant can add to a single message.
+const MAX_REACTIONS_PER_ACCOUNT: usize = 3;
+
+/// Upper bound on the storage a reaction uses in the receiving contract, apart from its content.
+const REACTION_STORAGE_OVERHEAD: StorageUsage = 256;
+
+#[near_bindgen]
+impl MessengerContract {
+    /// `react` flow:
+    /// 1. Call `ext_react` in the account the message was exchanged with.
+    /// 2. Store the reaction ourselves in a callback if it was accepted.
+    /// The deposit for the other account comes from the attached deposit or the caller's
+    /// storage balance.
+    #[payable]
+    pub fn react(
+        &mut self,
+        account: AccountId,
+        message_id: MessageId,
+        reaction: String,
+    ) -> Promise {
+        let caller = self.require_permission(Permission::SendToContacts);
+
+        require!(is_valid_reaction(&reaction), "Invalid reaction");
+        require!(
+            self.is_message_with(&account, &message_id),
+            "The message was not exchanged with this account"
+        );
+        let this = env::current_account_id();
+        let reactions = self.reactions.get(&message_id).unwrap_or_default();
+        require!(
+            check_reaction(&reactions, &this, &reaction) == ReactResponse::Reacted,
+            "You can not add this reaction to the message"
+        );
+        let deposit = compute_required_reaction_deposit(&reaction);
+        self.pay(&caller, deposit, None);
+
+        Self::ext(account)
+            .with_attached_deposit(deposit)
+            .ext_react(message_id, reaction.clone(), Some(caller))
+            .then(Self::ext(this).react_callback(message_id, reaction))
+    }
+
+    /// Part of the `react` flow. This method is called by another Messenger contract when it
+    /// wants to react to a message we exchanged with it.
+    #[payable]
+    pub fn ext_react(
+        &mut self,
+        message_id: MessageId,
+        reaction: String,
+        payer: Option<AccountId>,
+    ) -> ReactResponse {
+        let sender = env::predecessor_account_id();
+        if let Some(AccountStatus::Blocked) = self.accounts.get(&sender) {
+            refund_deposit(payer.as_ref());
+            return ReactResponse::Blocked;
+        }
+        if !self.is_message_with(&sender, &message_id) {
+            refund_deposit(payer.as_ref());
+            return ReactResponse::UnknownMessage;
+        }
+        if !is_valid_reaction(&reaction) {
+            refund_deposit(payer.as_ref());
+            return ReactResponse::InvalidReaction;
+        }
+        let mut reactions = self.reactions.get(&message_id).unwrap_or_default();
+        let response = check_reaction(&reactions, &sender, &reaction);
+        if response != ReactResponse::Reacted {
+            refund_deposit(payer.as_ref());
+            return response;
+        }
+        if !self.can_pay(&sender, compute_required_reaction_deposit(&reaction)) {
+            refund_deposit(payer.as_ref());
+            return ReactResponse::InsufficientDeposit;
+        }
+
+        let initial_storage = env::storage_usage();
+        reactions.push(Reaction {
+            account: sender.clone(),
+            reaction: reaction.clone(),
+            timestamp: U64(env::block_timestamp()),
+        });
+        self.reactions.insert(&message_id, &reactions);
+        self.pay_storage_used(&sender, initial_storage, payer.as_ref());
+
+        let receiver = env::current_account_id();
+        Event::message_reaction(&sender, &receiver, &message_id, &reaction).emit();
+
+        ReactResponse::Reacted
+    }
+
+    #[private]
+    pub fn react_callback(
+        &mut self,
+        message_id: MessageId,
+        reaction: String,
+        #[callback_result] response: Result<ReactResponse, PromiseError>,
+    ) -> ReactResponse {
+        match response {
+            Ok(ReactResponse::Reacted) => {
+                let mut reactions = self.reactions.get(&message_id).unwrap_or_default();
+                reactions.push(Reaction {
+                    account: env::current_account_id(),
+                    reaction,
+                    timestamp: U64(env::block_timestamp()),
+                });
+                self.reactions.insert(&message_id, &reactions);
+                ReactResponse::Reacted
+            }
+            Ok(other_response) => other_response,
+            Err(_e) => ReactResponse::InvalidAccount,
+        }
+    }
+}
+
+impl MessengerContract {
+    /// The reactions to a message, whether we sent or received it, oldest first.
+    pub(crate) fn reactions_of(&self, message_id: &MessageId) -> Vec<Reaction> {
+        self.reactions.get(message_id).unwrap_or_default()
+    }
+}
+
+fn is_valid_reaction(reaction: &str) -> bool {
+    !reaction.is_empty() && reaction.len() <= MAX_REACTION_LENGTH
+}
+
+/// Whether `account` can add `reaction` to a message with the given existing reactions.
+fn check_reaction(reactions: &[Reaction], account: &AccountId, reaction: &str) -> ReactResponse {
+    let own_reactions: Vec<&Reaction> =
+        reactions.iter().filter(|r| &r.account == account).collect();
+    if own_reactions.iter().any(|r| r.reaction == reaction) {
+        ReactResponse::DuplicateReaction
+    } else if own_reactions.len() >= MAX_REACTIONS_PER_ACCOUNT {
+        ReactResponse::TooManyReactions
+    } else {
+        ReactResponse::Reacted
+    }
+}
+
+fn compute_required_reaction_deposit(reaction: &str) -> Balance {
+    (REACTION_STORAGE_OVERHEAD as Balance + reaction.len() as Balance) * env::STORAGE_PRICE_PER_BYTE
+}
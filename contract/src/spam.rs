@@ -0,0 +1,130 @@
+//! Owner-configurable limits on what other accounts can send us. The policy applies to
+//! contact requests (`ext_add_contact`), and to messages (`receive_message`) and edits
+//! (`ext_edit_message`) from contacts. The length limit also applies to message requests
+//! (`ext_message_request`).
+//! Deposits above what the sending contract attaches are taken from its storage balance with
+//! us, so senders can meet a stricter policy with `storage_deposit`, or by attaching the
+//! deposit `view_message_deposit` asks for.
+
+use crate::{
+    compute_required_message_deposit,
+    types::{MessageBody, RateLimit, SpamPolicy},
+    MessengerContract, MessengerContractExt, ADD_CONTACT_DEPOSIT,
+};
+use near_sdk::{
+    borsh::{self, BorshDeserialize, BorshSerialize},
+    env,
+    json_types::U128,
+    near_bindgen, require, AccountId, Balance,
+};
+
+impl Default for SpamPolicy {
+    fn default() -> Self {
+        Self {
+            contact_request_deposit: U128(ADD_CONTACT_DEPOSIT),
+            message_price_multiplier: 1,
+            max_message_length: None,
+            rate_limit: None,
+        }
+    }
+}
+
+/// Number of messages an account sent us in the current window of the rate limit.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct MessageWindow {
+    start: u64,
+    count: u32,
+}
+
+#[near_bindgen]
+impl MessengerContract {
+    pub fn set_spam_policy(&mut self, policy: SpamPolicy) {
+        self.require_owner_only();
+        require!(
+            policy.message_price_multiplier >= 1,
+            "Messages must at least pay for their storage"
+        );
+        self.spam_policy = policy;
+    }
+
+    pub fn view_spam_policy(&self) -> SpamPolicy {
+        self.spam_policy
+    }
+
+    /// The deposit a contact must attach to send us `message`, or to edit a message into it.
+    /// Any part of it the message does not use is refunded.
+    pub fn view_message_deposit(&self, message: MessageBody) -> U128 {
+        U128(self.required_message_deposit(&message))
+    }
+
+    /// Delete the rate limit windows of `accounts` which have ended, or all of them if there
+    /// is no rate limit. Windows of accounts which are no longer contacts are deleted
+    /// with the contact. Returns the number of windows deleted.
+    pub fn prune_message_windows(&mut self, accounts: Vec<AccountId>) -> u32 {
+        self.require_owner_only();
+
+        let now = env::block_timestamp();
+        let mut pruned = 0;
+        for account in accounts {
+            let ended = match (
+                self.message_windows.get(&account),
+                self.spam_policy.rate_limit,
+            ) {
+                (Some(current), Some(rate_limit)) => {
+                    now >= current.start.saturating_add(rate_limit.window.0)
+                }
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if ended {
+                self.message_windows.remove(&account);
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+}
+
+impl MessengerContract {
+    /// The storage cost of a message, times the `message_price_multiplier`.
+    pub(crate) fn required_message_deposit(&self, message: &MessageBody) -> Balance {
+        compute_required_message_deposit(message)
+            * self.spam_policy.message_price_multiplier as Balance
+    }
+
+    pub(crate) fn is_too_large(&self, length: usize) -> bool {
+        self.spam_policy
+            .max_message_length
+            .map(|max| length > max as usize)
+            .unwrap_or(false)
+    }
+
+    /// Count a message from `sender` towards the rate limit, if it is allowed by it.
+    /// Returns false, without counting the message, if `sender` reached the limit.
+    pub(crate) fn try_count_message(&mut self, sender: &AccountId, timestamp: u64) -> bool {
+        let RateLimit {
+            max_messages,
+            window,
+        } = match self.spam_policy.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => return true,
+        };
+        let current = self
+            .message_windows
+            .get(sender)
+            .filter(|w| timestamp < w.start.saturating_add(window.0))
+            .unwrap_or(MessageWindow {
+                start: timestamp,
+                count: 0,
+            });
+        if current.count >= max_messages {
+            return false;
+        }
+        let updated = MessageWindow {
+            count: current.count + 1,
+            ..current
+        };
+        self.message_windows.insert(sender, &updated);
+        true
+    }
+}
@@ -0,0 +1,206 @@
+//! Follows the Near standard for storage management.
+//! See https://github.com/near/NEPs/blob/master/neps/nep-0145.md
+//!
+//! Any account can pre-fund a storage balance with this contract. The balance of the caller
+//! (the owner, or an account they granted permissions to) pays the deposits forwarded to
+//! other contracts (e.g. with `send_message`), while the balance of other Messenger
+//! contracts pays for the storage their messages and requests use here.
+//! In both cases the attached deposit is used first and any excess is refunded.
+//! A Messenger contract forwarding a deposit on behalf of one of its users names the user as
+//! the `payer`, so that whatever the other contract refunds is credited to the storage
+//! balance of that user, rather than kept by the forwarding contract.
+//! Registering an account takes the minimum balance, which pays for the storage of the
+//! registration itself and is returned by `storage_unregister`.
+
+use crate::{
+    types::{StorageBalance, StorageBalanceBounds},
+    MessengerContract, MessengerContractExt,
+};
+use near_sdk::{
+    assert_one_yocto, env, json_types::U128, near_bindgen, require, AccountId, Balance, Promise,
+    StorageUsage,
+};
+
+/// Storage used to register an account with the longest id: the key of its entry in
+/// `storage_balances` (prefix, length and id), the balance, and the overhead of a record.
+const STORAGE_REGISTRATION_SIZE: StorageUsage = 1 + 4 + 64 + 16 + 40;
+
+#[near_bindgen]
+impl MessengerContract {
+    /// A deposit for an account which is not registered must at least be the minimum
+    /// balance. Smaller deposits for another account (e.g. refunds from other Messenger
+    /// contracts to a payer who never registered) are passed on to that account instead.
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let predecessor = env::predecessor_account_id();
+        let account_id = account_id.unwrap_or_else(|| predecessor.clone());
+        let mut deposit = env::attached_deposit();
+        let available = match self.storage_balances.get(&account_id) {
+            Some(available) => available,
+            None => {
+                let min_balance = min_storage_balance();
+                if deposit < min_balance {
+                    require!(
+                        account_id != predecessor,
+                        "The deposit is less than the minimum storage balance"
+                    );
+                    if deposit > 0 {
+                        Promise::new(account_id).transfer(deposit);
+                    }
+                    return StorageBalance {
+                        total: U128(0),
+                        available: U128(0),
+                    };
+                }
+                deposit -= min_balance;
+                0
+            }
+        };
+        let available = if registration_only.unwrap_or(false) {
+            // Only the minimum balance is needed, so the rest of the deposit is refunded.
+            refund(deposit, None);
+            available
+        } else {
+            available + deposit
+        };
+        self.storage_balances.insert(&account_id, &available);
+        storage_balance(available)
+    }
+
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+
+        let account_id = env::predecessor_account_id();
+        let available = self
+            .storage_balances
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("The account is not registered"));
+        let amount = amount.map(|a| a.0).unwrap_or(available);
+        require!(
+            amount <= available,
+            "Amount exceeds the available storage balance"
+        );
+        let available = available - amount;
+        self.storage_balances.insert(&account_id, &available);
+        if amount > 0 {
+            Promise::new(account_id).transfer(amount);
+        }
+        storage_balance(available)
+    }
+
+    /// Nothing else is held on behalf of registered accounts, so `force` has no effect.
+    /// The available balance is returned along with the minimum balance.
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let _ = force;
+
+        let account_id = env::predecessor_account_id();
+        match self.storage_balances.remove(&account_id) {
+            Some(available) => {
+                Promise::new(account_id).transfer(available + min_storage_balance());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_balances.get(&account_id).map(storage_balance)
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(min_storage_balance()),
+            max: None,
+        }
+    }
+}
+
+impl MessengerContract {
+    /// Whether the attached deposit, together with the storage balance of `account`,
+    /// covers the given amount.
+    pub(crate) fn can_pay(&self, account: &AccountId, amount: Balance) -> bool {
+        let available = self.storage_balances.get(account).unwrap_or(0);
+        env::attached_deposit() + available >= amount
+    }
+
+    /// Pay the given amount using the attached deposit first, then the storage balance of
+    /// `account`. Any part of the attached deposit which is not needed is refunded (see
+    /// `refund`). This must be called at most once per method call.
+    pub(crate) fn pay(&mut self, account: &AccountId, amount: Balance, payer: Option<&AccountId>) {
+        let deposit = env::attached_deposit();
+        if deposit >= amount {
+            refund(deposit - amount, payer);
+            return;
+        }
+        let shortfall = amount - deposit;
+        let available = self.storage_balances.get(account).unwrap_or(0);
+        require!(available >= shortfall, "Insufficient deposit");
+        self.storage_balances
+            .insert(account, &(available - shortfall));
+    }
+
+    /// Pay for the storage used since `initial_storage` (see `pay`).
+    pub(crate) fn pay_storage_used(
+        &mut self,
+        account: &AccountId,
+        initial_storage: StorageUsage,
+        payer: Option<&AccountId>,
+    ) {
+        let used = env::storage_usage().saturating_sub(initial_storage);
+        self.pay(
+            account,
+            (used as Balance) * env::STORAGE_PRICE_PER_BYTE,
+            payer,
+        );
+    }
+}
+
+/// Return the attached deposit, for calls which did not use it (see `refund`).
+pub(crate) fn refund_deposit(payer: Option<&AccountId>) {
+    refund(env::attached_deposit(), payer);
+}
+
+/// Return part of the attached deposit to the predecessor. If the predecessor is a Messenger
+/// contract which named a `payer` for the deposit, then the refund goes to the storage balance
+/// of the payer with that contract.
+pub(crate) fn refund(amount: Balance, payer: Option<&AccountId>) {
+    refund_to(env::predecessor_account_id(), amount, payer);
+}
+
+/// Send `amount` to the Messenger contract `account`. If a `payer` paid it on behalf of the
+/// contract, then it goes to the storage balance of the payer with that contract instead.
+pub(crate) fn refund_to(account: AccountId, amount: Balance, payer: Option<&AccountId>) {
+    if amount == 0 {
+        return;
+    }
+    match payer {
+        Some(payer) => {
+            MessengerContract::ext(account)
+                .with_attached_deposit(amount)
+                .storage_deposit(Some(payer.clone()), None);
+        }
+        None => {
+            Promise::new(account).transfer(amount);
+        }
+    }
+}
+
+fn min_storage_balance() -> Balance {
+    (STORAGE_REGISTRATION_SIZE as Balance) * env::STORAGE_PRICE_PER_BYTE
+}
+
+fn storage_balance(available: Balance) -> StorageBalance {
+    // Storage which has been paid for is no longer part of the balance, so the whole
+    // balance is available apart from the minimum balance of the registration.
+    StorageBalance {
+        total: U128(available + min_storage_balance()),
+        available: U128(available),
+    }
+}
@@ -1,11 +1,28 @@
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
     env,
-    json_types::U64,
+    json_types::{Base58CryptoHash, Base64VecU8, U128, U64},
     serde::{Deserialize, Serialize},
     AccountId,
 };
 
+/// Storage balance of an account, as defined in the storage management standard (NEP-145).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// Bounds on the storage balance of an account, as defined in the storage management
+/// standard (NEP-145).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
 /// Different possible responses when we attempt to add an account as a contact.
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(crate = "near_sdk::serde")]
@@ -24,6 +41,66 @@ pub enum AddContactResponse {
     Accepted,
 }
 
+/// Different possible responses when we attempt to retract a message we sent.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RetractMessageResponse {
+    /// This account does not support the Messenger protocol.
+    InvalidAccount,
+    /// The account has no message with the given id.
+    UnknownMessage,
+    /// The message was sent by a different account.
+    NotSender,
+    /// The message was removed.
+    Retracted,
+}
+
+/// Different possible responses when we attempt to edit a message we sent.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum EditMessageResponse {
+    /// This account does not support the Messenger protocol.
+    InvalidAccount,
+    /// This account has blocked us.
+    Blocked,
+    /// The account has no message with the given id.
+    UnknownMessage,
+    /// The message was sent by a different account.
+    NotSender,
+    /// The edit did not come with a sufficient deposit.
+    InsufficientDeposit,
+    /// The new content was encrypted with a key we did not publish.
+    UnknownEncryptionKey,
+    /// The sender already sent as many messages and edits as our `SpamPolicy` allows for now.
+    RateLimited,
+    /// The new content is longer than our `SpamPolicy` allows.
+    TooLarge,
+    /// The message now has the new content.
+    Edited,
+}
+
+/// Different possible responses when we react to a message.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ReactResponse {
+    /// This account does not support the Messenger protocol.
+    InvalidAccount,
+    /// This account has blocked us.
+    Blocked,
+    /// The account has no message with the given id exchanged with us.
+    UnknownMessage,
+    /// The reaction is empty or too long.
+    InvalidReaction,
+    /// We already reacted to the message with the same reaction.
+    DuplicateReaction,
+    /// We already added as many reactions to the message as we are allowed.
+    TooManyReactions,
+    /// The reaction did not come with a sufficient deposit.
+    InsufficientDeposit,
+    /// The reaction was added to the message.
+    Reacted,
+}
+
 /// Different possible responses when we accept an add contact request.
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(crate = "near_sdk::serde")]
@@ -40,14 +117,68 @@ pub enum AcceptContactResponse {
     Accepted,
 }
 
+/// Different possible responses when we decline a contact request.
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(crate = "near_sdk::serde")]
+pub enum DeclineContactResponse {
+    /// This account does not support the Messenger protocol.
+    InvalidAccount,
+    /// The account has no pending request to us (e.g. it cancelled it).
+    UnknownRequest,
+    /// The request was removed on both sides.
+    Declined,
+}
+
+/// Different possible responses when we cancel a contact request we sent.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum CancelContactResponse {
+    /// This account does not support the Messenger protocol.
+    InvalidAccount,
+    /// The account has no pending request from us (e.g. it declined it).
+    UnknownRequest,
+    /// The request was removed on both sides.
+    Cancelled,
+}
+
+/// Different possible responses when a contact tells us they removed us as a contact.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RemoveContactResponse {
+    /// This account does not support the Messenger protocol.
+    InvalidAccount,
+    /// We were not contacts (e.g. we have blocked the account), so nothing changed.
+    NotConnected,
+    /// The contact was removed on both sides.
+    Removed,
+}
+
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
 pub enum MessageResponse {
     InvalidAccount,
     Blocked,
     NotConnected,
     InsufficientDeposit,
     Received,
+    /// Another message, sent or received, is already stored under the same id, so this
+    /// one was rejected.
+    DuplicateMessage,
+    /// The message was encrypted with a key we did not publish.
+    UnknownEncryptionKey,
+    /// The message replies to a message which is not part of our conversation with the sender.
+    UnknownReplyTo,
+    /// A message request was sent, but we are already contacts.
+    AlreadyConnected,
+    /// A message request was sent, but a contact request (possibly with a message) between us
+    /// is already pending.
+    PendingRequest,
+    /// The sender already sent as many messages as our `SpamPolicy` allows for now.
+    RateLimited,
+    /// The content is longer than our `SpamPolicy` allows.
+    TooLarge,
 }
 
 /// Unique ID for messages the contract receives.
@@ -80,25 +211,560 @@ impl TryFrom<String> for MessageId {
     }
 }
 
+/// The content of a message.
+///
+/// Plain text is represented by a JSON string, as it was before other kinds of content were
+/// supported. Other kinds are JSON objects with a `type` field naming the kind
+/// (e.g. `{"type": "markdown", "text": "**Hi**"}`). Encrypted payloads may also be given
+/// without the `type` field, as they were when first supported.
+///
+/// New variants must be added at the end, so that content already stored as a `MessageBody`
+/// (and the ids of the messages, which are hashes of their content) stays the same.
+/// The first release stored the content as a bare `String`, which `migrate` converts into
+/// `Text`, keeping the ids those messages were stored under.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(from = "MessageBodyJson", into = "MessageBodyJson")]
+pub enum MessageBody {
+    /// Text which anyone can read by viewing the contract state.
+    Text(String),
+    /// Content only the recipient can read (see the `encryption` module).
+    Encrypted(EncryptedPayload),
+    /// Text to be rendered as Markdown.
+    Markdown(String),
+    /// A link to a web page.
+    Link { url: String, title: Option<String> },
+    /// A file stored off-chain.
+    Attachment(Attachment),
+}
+
+impl MessageBody {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Self::Text(_) => MessageKind::Text,
+            Self::Encrypted(_) => MessageKind::Encrypted,
+            Self::Markdown(_) => MessageKind::Markdown,
+            Self::Link { .. } => MessageKind::Link,
+            Self::Attachment(_) => MessageKind::Attachment,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// The number of bytes of content, which is what the storage deposit is based on.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Text(text) | Self::Markdown(text) => text.len(),
+            Self::Encrypted(payload) => payload.len(),
+            Self::Link { url, title } => url.len() + title.as_ref().map_or(0, String::len),
+            Self::Attachment(attachment) => attachment.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<String> for MessageBody {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl From<&str> for MessageBody {
+    fn from(value: &str) -> Self {
+        Self::Text(value.into())
+    }
+}
+
+/// The kinds of content a message can have, so that clients know how to render it.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Text,
+    Encrypted,
+    Markdown,
+    Link,
+    Attachment,
+}
+
+/// Reference to a file stored off-chain.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Attachment {
+    /// SHA-256 hash of the file, so that its integrity can be checked.
+    pub content_hash: Base58CryptoHash,
+    pub mime_type: String,
+    /// Size of the file in bytes.
+    pub size: U64,
+    /// Where the file can be downloaded from (e.g. an IPFS or HTTPS URL).
+    pub location: String,
+}
+
+impl Attachment {
+    pub fn len(&self) -> usize {
+        std::mem::size_of::<near_sdk::CryptoHash>()
+            + self.mime_type.len()
+            + std::mem::size_of::<u64>()
+            + self.location.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.location.is_empty()
+    }
+}
+
+/// JSON representation of `MessageBody`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(untagged)]
+enum MessageBodyJson {
+    Text(String),
+    Typed(TypedMessageBody),
+    /// Encrypted payloads without a `type` field. Only used when deserializing.
+    Encrypted(EncryptedPayload),
+}
+
+/// Content given as a JSON object, tagged with its kind.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TypedMessageBody {
+    Text { text: String },
+    Encrypted(EncryptedPayload),
+    Markdown { text: String },
+    Link { url: String, title: Option<String> },
+    Attachment(Attachment),
+}
+
+impl From<MessageBodyJson> for MessageBody {
+    fn from(value: MessageBodyJson) -> Self {
+        match value {
+            MessageBodyJson::Text(text) => Self::Text(text),
+            MessageBodyJson::Encrypted(payload) => Self::Encrypted(payload),
+            MessageBodyJson::Typed(TypedMessageBody::Text { text }) => Self::Text(text),
+            MessageBodyJson::Typed(TypedMessageBody::Encrypted(payload)) => {
+                Self::Encrypted(payload)
+            }
+            MessageBodyJson::Typed(TypedMessageBody::Markdown { text }) => Self::Markdown(text),
+            MessageBodyJson::Typed(TypedMessageBody::Link { url, title }) => {
+                Self::Link { url, title }
+            }
+            MessageBodyJson::Typed(TypedMessageBody::Attachment(attachment)) => {
+                Self::Attachment(attachment)
+            }
+        }
+    }
+}
+
+impl From<MessageBody> for MessageBodyJson {
+    fn from(value: MessageBody) -> Self {
+        let typed = match value {
+            // Plain text stays a bare string, so existing clients can still read it.
+            MessageBody::Text(text) => return Self::Text(text),
+            // The `type` field is added next to the fields of the payload, so clients
+            // expecting an untagged payload can still read it.
+            MessageBody::Encrypted(payload) => TypedMessageBody::Encrypted(payload),
+            MessageBody::Markdown(text) => TypedMessageBody::Markdown { text },
+            MessageBody::Link { url, title } => TypedMessageBody::Link { url, title },
+            MessageBody::Attachment(attachment) => TypedMessageBody::Attachment(attachment),
+        };
+        Self::Typed(typed)
+    }
+}
+
+/// Identifies one of the encryption keys published by an account.
+pub type EncryptionKeyId = u32;
+
+/// Content encrypted for the recipient's published encryption key.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EncryptedPayload {
+    pub ciphertext: Base64VecU8,
+    pub nonce: Base64VecU8,
+    /// The recipient's key the content was encrypted for.
+    pub key_id: EncryptionKeyId,
+}
+
+impl EncryptedPayload {
+    pub fn len(&self) -> usize {
+        self.ciphertext.0.len() + self.nonce.0.len() + std::mem::size_of::<EncryptionKeyId>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ciphertext.0.is_empty()
+    }
+}
+
+/// Length of the X25519 public keys used for encryption.
+pub const ENCRYPTION_PUBLIC_KEY_LENGTH: usize = 32;
+
+/// A public key other accounts can encrypt messages for us with.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EncryptionKey {
+    pub id: EncryptionKeyId,
+    pub public_key: Base64VecU8,
+}
+
 #[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Message {
     pub sender: AccountId,
     pub timestamp: U64,
-    pub content: String,
+    pub content: MessageBody,
     pub parent_id: Option<MessageId>,
+    /// The content has been changed by the sender since the message was first received.
+    /// Earlier versions of the content are kept as `MessageRevision`s.
+    #[serde(default)]
+    pub edited: bool,
+    /// The message in the same conversation this message is a reply to, if any.
+    /// Unlike `parent_id`, this is chosen by the sender.
+    #[serde(default)]
+    pub reply_to: Option<MessageId>,
+    /// Reactions are stored separately from the message, and only filled in when it is viewed.
+    #[borsh_skip]
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
 }
 
 impl Message {
     pub fn id(&self) -> MessageId {
-        let bytes = self
+        hash_message(self)
+    }
+}
+
+/// A message we have sent to one of our contacts.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SentMessage {
+    pub recipient: AccountId,
+    pub timestamp: U64,
+    pub content: MessageBody,
+    /// The previous message we sent to the same recipient.
+    pub parent_id: Option<MessageId>,
+    #[serde(default)]
+    pub edited: bool,
+    #[serde(default)]
+    pub reply_to: Option<MessageId>,
+    #[borsh_skip]
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+}
+
+impl SentMessage {
+    /// The id is shared with the recipient, so both sides of the conversation
+    /// refer to the message the same way.
+    pub fn id(&self) -> MessageId {
+        hash_message(self)
+    }
+
+    /// Express this message as it would be seen by the recipient.
+    pub fn into_message(self, sender: AccountId) -> Message {
+        Message {
+            sender,
+            timestamp: self.timestamp,
+            content: self.content,
+            parent_id: self.parent_id,
+            edited: self.edited,
+            reply_to: self.reply_to,
+            reactions: self.reactions,
+        }
+    }
+}
+
+/// Left in place of a message that was removed, so that the parent chain of the
+/// thread it was part of can still be followed.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Tombstone {
+    pub parent_id: Option<MessageId>,
+    /// The time the removed message was originally sent or received.
+    pub timestamp: U64,
+    pub removed_at: U64,
+}
+
+/// A previous version of the content of an edited message.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessageRevision {
+    pub content: MessageBody,
+    /// The time this content was replaced by a newer version.
+    pub replaced_at: U64,
+}
+
+/// A reaction (e.g. an emoji) one of the participants of a conversation added to a message.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reaction {
+    pub account: AccountId,
+    pub reaction: String,
+    pub timestamp: U64,
+}
+
+/// What we know about whether a message we sent reached the recipient.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DeliveryStatus {
+    /// The message was sent, but the recipient has not responded yet.
+    Pending,
+    /// The recipient stored the message.
+    Delivered,
+    /// The recipient did not accept the message for the given reason.
+    Rejected { reason: MessageResponse },
+    /// The call to the recipient failed (e.g. it does not support the Messenger protocol).
+    Failed,
+}
+
+/// Message automatically sent back to contacts when we receive a message from them.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AutoReply {
+    pub message: String,
+    /// The auto-reply is not sent before this time (nanoseconds since the Unix epoch).
+    pub start: Option<U64>,
+    /// The auto-reply is not sent after this time (nanoseconds since the Unix epoch).
+    pub end: Option<U64>,
+    /// Only reply to the first message from each contact while the auto-reply is set.
+    pub first_message_only: bool,
+}
+
+impl AutoReply {
+    pub fn is_active(&self, timestamp: u64) -> bool {
+        let started = self.start.map(|t| t.0 <= timestamp).unwrap_or(true);
+        let ended = self.end.map(|t| t.0 < timestamp).unwrap_or(false);
+        started && !ended
+    }
+}
+
+/// Limits the owner sets on what other accounts can send us, to discourage spam.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SpamPolicy {
+    /// Deposit which must come with a contact request. Only the storage the request uses
+    /// is kept.
+    pub contact_request_deposit: U128,
+    /// Messages pay this many times the cost of the storage they use.
+    pub message_price_multiplier: u32,
+    /// Maximum length of the content of a message, in bytes.
+    pub max_message_length: Option<u32>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Maximum number of messages each account can send us in a window of time.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RateLimit {
+    pub max_messages: u32,
+    /// Length of the window in nanoseconds.
+    pub window: U64,
+}
+
+/// What happens to the fee paid with a message request once the owner handles it.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MessageRequestRefund {
+    /// The fee is kept.
+    Never,
+    /// The fee is refunded if the request is accepted, and kept otherwise.
+    OnAccept,
+    /// The fee is refunded whether the request is accepted or declined (but not if the
+    /// sender is blocked).
+    Always,
+}
+
+/// Terms under which accounts which are not our contacts can send us a message request.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessageRequestPolicy {
+    /// Fee to attach to a message request, on top of the deposit for its storage.
+    pub fee: U128,
+    pub refund: MessageRequestRefund,
+}
+
+/// A message from an account which is not our contact. It is also a request to become
+/// contacts, which the owner can accept or decline.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessageRequest {
+    pub id: MessageId,
+    pub message: Message,
+    /// The fee which was paid with the request.
+    pub fee: U128,
+    /// The refund policy at the time the request was received.
+    pub refund: MessageRequestRefund,
+    /// The account which paid the fee on behalf of the sender, if any. Refunds of the fee go
+    /// to its storage balance with the sender.
+    pub payer: Option<AccountId>,
+}
+
+/// What the owner keeps about one of their contacts, so that it is shared by all their
+/// devices. None of it is visible to the contact.
+#[derive(
+    Debug, Default, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContactMetadata {
+    pub nickname: Option<String>,
+    pub notes: Option<String>,
+    /// Messages from a muted contact are received as already read.
+    pub muted: bool,
+    pub archived: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContactWithMetadata {
+    pub account: AccountId,
+    pub metadata: ContactMetadata,
+}
+
+/// Actions the owner can allow other accounts to take on their behalf.
+#[derive(
+    Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Permission {
+    /// Mark received messages as read. Messages can be viewed by anyone without permission.
+    ReadOnly,
+    /// Send, edit, retract and react to messages, in conversations and groups.
+    SendToContacts,
+    /// Add, accept and block contacts, and manage the members of our groups.
+    ManageContacts,
+}
+
+/// Permissions the owner granted to another account (e.g. another device or a bot).
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Delegate {
+    pub permissions: Vec<Permission>,
+    /// The permissions can not be used after this time (nanoseconds since the Unix epoch).
+    pub expires_at: Option<U64>,
+}
+
+impl Delegate {
+    pub fn allows(&self, permission: Permission, timestamp: u64) -> bool {
+        let expired = self.expires_at.map(|t| t.0 <= timestamp).unwrap_or(false);
+        !expired && self.permissions.contains(&permission)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DelegateWithAccount {
+    pub account: AccountId,
+    pub delegate: Delegate,
+}
+
+/// Unique ID for group conversations. It is shared by all the members of the group.
+#[derive(
+    Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(into = "String")]
+#[serde(try_from = "String")]
+pub struct GroupId(pub near_sdk::CryptoHash);
+
+// Use the same base58 representation as MessageId.
+impl From<GroupId> for String {
+    fn from(value: GroupId) -> Self {
+        MessageId(value.0).into()
+    }
+}
+
+impl TryFrom<String> for GroupId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        MessageId::try_from(value).map(|id| Self(id.0))
+    }
+}
+
+/// A group conversation. The group is managed by its creator, who sends
+/// updates of the group to all members when it changes.
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Group {
+    pub creator: AccountId,
+    pub name: String,
+    /// All the members of the group, including the creator.
+    pub members: Vec<AccountId>,
+    /// Time the group was created.
+    pub created_at: U64,
+}
+
+impl Group {
+    /// The id of a group is derived from its creator, name and creation time, which never change.
+    pub fn id(&self) -> GroupId {
+        let bytes = (&self.creator, &self.name, self.created_at.0)
             .try_to_vec()
-            .unwrap_or_else(|_e| env::panic_str("Failed to serialize message"));
-        let hash = env::sha256_array(&bytes);
-        MessageId(hash)
+            .unwrap_or_else(|_e| env::panic_str("Failed to serialize group"));
+        GroupId(env::sha256_array(&bytes))
+    }
+
+    pub fn is_member(&self, account: &AccountId) -> bool {
+        self.members.contains(account)
     }
 }
 
+#[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupWithId {
+    pub id: GroupId,
+    pub group: Group,
+}
+
+/// Different possible responses when the creator of a group sends us an update of it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum GroupUpdateResponse {
+    /// We have blocked the creator of the group.
+    Blocked,
+    /// We only join groups created by our contacts.
+    NotConnected,
+    /// The update did not come with a sufficient deposit.
+    InsufficientDeposit,
+    /// The group is managed by a different account.
+    NotCreator,
+    /// The id of the group does not match the group.
+    InvalidId,
+    /// The group was stored.
+    Updated,
+}
+
+/// The ID of a message in a group conversation also depends on the group, so that
+/// identical messages in different groups have different IDs.
+pub fn group_message_id(group_id: &GroupId, message: &Message) -> MessageId {
+    hash_message(&(group_id, message))
+}
+
+fn hash_message<T: BorshSerialize>(message: &T) -> MessageId {
+    let bytes = message
+        .try_to_vec()
+        .unwrap_or_else(|_e| env::panic_str("Failed to serialize message"));
+    let hash = env::sha256_array(&bytes);
+    MessageId(hash)
+}
+
 #[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MessageWithId {
@@ -106,6 +772,63 @@ pub struct MessageWithId {
     pub message: Message,
 }
 
+/// Part of a list returned by a view method. If there is more to the list, the next part can
+/// be viewed by passing `next` as the cursor (e.g. `from_index` or `before`) to the same method.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Page<T, C> {
+    pub items: Vec<T>,
+    pub next: Option<C>,
+}
+
+impl<T, C> Default for Page<T, C> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            next: None,
+        }
+    }
+}
+
+/// Position in the history of a conversation: the oldest message shown so far from each
+/// side of it. `None` means no message from that side has been shown yet.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConversationCursor {
+    pub received: Option<MessageId>,
+    pub sent: Option<MessageId>,
+}
+
+/// Position in a purge which has not gone through everything yet.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurgeCursor {
+    /// The most recent message (or tombstone) kept so far in the thread being purged.
+    /// `None` means every message gone through so far in the thread was deleted.
+    pub kept: Option<MessageId>,
+    /// Whether the received messages of the conversation have all been gone through, so
+    /// that the sent ones are being purged.
+    #[serde(default)]
+    pub sent: bool,
+    /// When purging the whole inbox, the account whose thread is being purged.
+    #[serde(default)]
+    pub account: Option<AccountId>,
+    /// When purging the whole inbox, the position in the read messages to look for the
+    /// next message to delete from.
+    #[serde(default)]
+    pub index: u64,
+}
+
+/// Result of deleting part of the messages. If they have not been gone through completely,
+/// the rest of them can be purged by passing `next` as the cursor to the same method.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurgeResult {
+    /// Number of bytes of storage freed.
+    pub freed: U64,
+    pub next: Option<PurgeCursor>,
+}
+
 #[derive(Debug, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct UnreadMessageView {
@@ -136,9 +859,35 @@ pub enum AccountStatus {
     /// We received a request to connect with the account, but have not accepted it yet.
     ReceivedPendingRequest,
     /// We have blocked interactions with that account.
-    /// Being blocked is part of the model, but there is no functional way to block a user.
-    /// EXERCISE: implement functions for (un)blocking a user. Add integrations test for them.
     Blocked,
     /// Known account that we can interact with.
     Contact,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::serde_json::{self, json};
+
+    #[test]
+    fn test_message_body_json_compatibility() {
+        // Content in the formats used before the kinds of content were tagged
+        let text: MessageBody = serde_json::from_value(json!("Hello")).unwrap();
+        assert_eq!(text, MessageBody::Text("Hello".into()));
+        let payload = json!({"ciphertext": "AQID", "nonce": "BAUG", "key_id": 1});
+        let encrypted: MessageBody = serde_json::from_value(payload.clone()).unwrap();
+        assert_eq!(encrypted.kind(), MessageKind::Encrypted);
+
+        // Text is still written as a bare string, and other kinds have a `type` field
+        assert_eq!(serde_json::to_value(&text).unwrap(), json!("Hello"));
+        let mut tagged_payload = payload;
+        tagged_payload["type"] = json!("encrypted");
+        assert_eq!(serde_json::to_value(&encrypted).unwrap(), tagged_payload);
+        let markdown: MessageBody =
+            serde_json::from_value(json!({"type": "markdown", "text": "**Hello**"})).unwrap();
+        assert_eq!(markdown, MessageBody::Markdown("**Hello**".into()));
+        let text: MessageBody =
+            serde_json::from_value(json!({"type": "text", "text": "Hello"})).unwrap();
+        assert_eq!(text, MessageBody::Text("Hello".into()));
+    }
+}
@@ -4,7 +4,17 @@ mod tests {
         tokio, utils,
         workspaces::{self, result::ExecutionFinalResult, AccountId},
     };
-    use near_messenger::{events::Event, types};
+    use near_messenger::{encryption, events::Event, types};
+
+    /// Cost of a byte of storage in yoctoNear.
+    const STORAGE_PRICE_PER_BYTE: u128 = 10_000_000_000_000_000_000;
+    /// Storage, apart from its content, a message is expected to use in the receiving
+    /// contract (see `compute_required_message_deposit`).
+    const MESSAGE_STORAGE_OVERHEAD: u128 = 1024;
+    /// Minimum storage balance, paying for the registration of an account with the longest id.
+    const MIN_STORAGE_BALANCE: u128 = 125 * STORAGE_PRICE_PER_BYTE;
+    /// Number of revisions kept of an edited message (see `MAX_MESSAGE_REVISIONS`).
+    const MAX_MESSAGE_REVISIONS: usize = 10;
 
     // This test is for the "happy path" of adding a contact, having them accept and then sending
     // a message. It shows that the basic contract functionality works.
@@ -50,8 +60,9 @@ mod tests {
             .args(b"{}".to_vec())
             .await
             .unwrap()
-            .json()
-            .unwrap();
+            .json::<types::Page<AccountId, u64>>()
+            .unwrap()
+            .items;
         assert_eq!(pending_contacts.len(), 1);
 
         // Bob accepts Alice as a contact
@@ -88,8 +99,9 @@ mod tests {
             .args(b"{}".to_vec())
             .await
             .unwrap()
-            .json()
-            .unwrap();
+            .json::<types::Page<AccountId, u64>>()
+            .unwrap()
+            .items;
         assert_eq!(pending_contacts.len(), 0);
 
         // Alice sends Bob a message
@@ -115,11 +127,27 @@ mod tests {
         let event_details = event.as_message_received().unwrap();
         assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
         assert_eq!(event_details.receiver.as_str(), bob.contract.id().as_str());
+        // Event for Alice learning the message was delivered.
+        let event = parse_event(&response, 2);
+        let event_details = event.as_message_delivery().unwrap();
+        assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
+        assert_eq!(*event_details.status, types::DeliveryStatus::Delivered);
         // Check output is correct.
         assert_eq!(
             response.json::<types::MessageResponse>().unwrap(),
             types::MessageResponse::Received
         );
+        let status: Option<types::DeliveryStatus> = alice
+            .owner
+            .view(alice.contract.id(), "view_delivery_status")
+            .args_json(serde_json::json!({
+                "message_id": event_details.message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(status, Some(types::DeliveryStatus::Delivered));
 
         let unread: Vec<types::UnreadMessageView> = bob
             .owner
@@ -127,8 +155,9 @@ mod tests {
             .args(b"{}".to_vec())
             .await
             .unwrap()
-            .json()
-            .unwrap();
+            .json::<types::Page<types::UnreadMessageView, u64>>()
+            .unwrap()
+            .items;
         assert_eq!(unread.len(), 1);
 
         let messages: Vec<types::MessageWithId> = bob
@@ -139,24 +168,2920 @@ mod tests {
             }))
             .await
             .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items;
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages.first().unwrap().message.content.as_text(),
+            Some("Hello, Bob!")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_account() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        let charlie = setup_messenger_contract("charlie.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        // Charlie sends Bob a contact request, then Bob blocks both Alice and Charlie
+        let response = add_contact(&charlie, &bob).await;
+        assert_eq!(
+            response.json::<types::AddContactResponse>().unwrap(),
+            types::AddContactResponse::Pending
+        );
+        for account in [&alice, &charlie] {
+            let response = bob
+                .owner
+                .call(bob.contract.id(), "block_account")
+                .args_json(serde_json::json!({
+                    "account": account.contract.id(),
+                }))
+                .transact()
+                .await
+                .unwrap();
+            assert!(response.is_success());
+            let event = parse_event(&response, 0);
+            let event_details = event.as_blocked_account().unwrap();
+            assert_eq!(event_details.this.as_str(), bob.contract.id().as_str());
+            assert_eq!(
+                event_details.account.as_str(),
+                account.contract.id().as_str()
+            );
+        }
+
+        // The blocked request is no longer pending
+        let pending_contacts: Vec<AccountId> = bob
+            .owner
+            .view(bob.contract.id(), "view_pending_contacts")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<AccountId, u64>>()
+            .unwrap()
+            .items;
+        assert!(pending_contacts.is_empty());
+
+        // Messages and contact requests from blocked accounts are rejected
+        let response = send_message(&alice, &bob, "Hello, Bob!").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Blocked
+        );
+        let event = parse_event(&response, 1);
+        let event_details = event.as_message_delivery().unwrap();
+        assert_eq!(
+            *event_details.status,
+            types::DeliveryStatus::Rejected {
+                reason: types::MessageResponse::Blocked
+            }
+        );
+        let response = add_contact(&charlie, &bob).await;
+        assert_eq!(
+            response.json::<types::AddContactResponse>().unwrap(),
+            types::AddContactResponse::Blocked
+        );
+
+        // After unblocking, Alice is not automatically a contact again
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "unblock_account")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_success());
+        let event = parse_event(&response, 0);
+        assert!(event.as_blocked_account().is_some());
+        let response = send_message(&alice, &bob, "Hello again, Bob!").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::NotConnected
+        );
+
+        // Only blocked accounts can be unblocked
+        let result = bob
+            .owner
+            .call(bob.contract.id(), "unblock_account")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert!(result.is_failure());
+
+        let messages: Vec<types::MessageWithId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items;
+        assert!(messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_view_conversation() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let contents = ["Hello, Bob!", "Hi, Alice!", "How are you?"];
+        let mut message_ids = Vec::new();
+        for (i, content) in contents.iter().enumerate() {
+            let (from, to) = if i % 2 == 0 {
+                (&alice, &bob)
+            } else {
+                (&bob, &alice)
+            };
+            let response = send_message(from, to, content).await;
+            let sent_id = parse_event(&response, 0)
+                .as_message_sent()
+                .unwrap()
+                .message_id
+                .clone()
+                .unwrap();
+            let received_id = parse_event(&response, 1)
+                .as_message_received()
+                .unwrap()
+                .message_id
+                .clone();
+            // Both contracts use the same id for the message
+            assert_eq!(sent_id, received_id);
+            message_ids.push(sent_id.into_owned());
+        }
+
+        // Both sides of the conversation are visible from either contract
+        for (this, other) in [(&alice, &bob), (&bob, &alice)] {
+            let messages: Vec<types::MessageWithId> = this
+                .owner
+                .view(this.contract.id(), "view_conversation")
+                .args_json(serde_json::json!({
+                    "account": other.contract.id(),
+                }))
+                .await
+                .unwrap()
+                .json::<types::Page<types::MessageWithId, types::ConversationCursor>>()
+                .unwrap()
+                .items;
+            assert_eq!(messages.len(), contents.len());
+            for (i, message) in messages.iter().enumerate() {
+                let expected_sender = if i % 2 == 0 { &alice } else { &bob };
+                assert_eq!(message.id, message_ids[i]);
+                assert_eq!(message.message.content.as_text(), Some(contents[i]));
+                assert_eq!(
+                    message.message.sender.as_str(),
+                    expected_sender.contract.id().as_str()
+                );
+            }
+        }
+
+        // The size limit keeps the most recent messages, and the rest can be viewed
+        // with the returned cursor
+        let page: types::Page<types::MessageWithId, types::ConversationCursor> = alice
+            .owner
+            .view(alice.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "max_size": 2,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        let ids: Vec<types::MessageId> = page.items.into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, message_ids[1..]);
+        let page: types::Page<types::MessageWithId, types::ConversationCursor> = alice
+            .owner
+            .view(alice.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "max_size": 2,
+                "before": page.next.unwrap(),
+            }))
+            .await
+            .unwrap()
             .json()
             .unwrap();
+        let ids: Vec<types::MessageId> = page.items.into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, message_ids[..1]);
+        assert!(page.next.is_none());
 
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages.first().unwrap().message.content, "Hello, Bob!");
+        // The same works for a single side of the conversation
+        let page: types::Page<types::MessageWithId, types::MessageId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+                "max_size": 1,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(page.items[0].id, message_ids[2]);
+        assert_eq!(page.next, Some(message_ids[2]));
+        let page: types::Page<types::MessageWithId, types::MessageId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+                "max_size": 1,
+                "before": page.next.unwrap(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(page.items[0].id, message_ids[0]);
+        assert!(page.next.is_none());
     }
 
-    async fn setup_messenger_contract(
-        account_name: &str,
-        worker: &workspaces::Worker<workspaces::network::Sandbox>,
-    ) -> MessengerInstance {
-        // This is needed because of a quirk of how `cargo-near` works. It doesn't handle
-        // cargo workspaces properly yet.
-        tokio::fs::create_dir_all("../target/near/near_messenger")
+    #[tokio::test]
+    async fn test_auto_reply() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        // Both Alice and Bob set an auto-reply, but they must not reply to each other forever
+        for (this, message) in [(&alice, "Alice is away"), (&bob, "Bob is away")] {
+            this.owner
+                .call(this.contract.id(), "set_auto_reply")
+                .args_json(serde_json::json!({
+                    "auto_reply": {
+                        "message": message,
+                        "start": null,
+                        "end": null,
+                        "first_message_only": true,
+                    },
+                }))
+                .transact()
+                .await
+                .unwrap()
+                .into_result()
+                .unwrap();
+            this.owner
+                .call(this.contract.id(), "fund_auto_reply")
+                .deposit(1_000_000_000_000_000_000_000_000)
+                .transact()
+                .await
+                .unwrap()
+                .into_result()
+                .unwrap();
+        }
+
+        // Bob only replies to the first message
+        for content in ["Hello, Bob!", "Are you there?"] {
+            let response = send_message(&alice, &bob, content).await;
+            assert_eq!(
+                response.json::<types::MessageResponse>().unwrap(),
+                types::MessageResponse::Received
+            );
+        }
+
+        let messages: Vec<types::MessageWithId> = alice
+            .owner
+            .view(alice.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::ConversationCursor>>()
+            .unwrap()
+            .items;
+        let contents: Vec<&str> = messages
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap())
+            .collect();
+        assert_eq!(contents, ["Hello, Bob!", "Bob is away", "Are you there?"]);
+
+        // Alice did not auto-reply to Bob's auto-reply
+        let messages: Vec<types::MessageWithId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items;
+        assert_eq!(messages.len(), 2);
+
+        // An auto-reply that is rejected does not use up the budget
+        alice
+            .owner
+            .call(alice.contract.id(), "set_spam_policy")
+            .args_json(serde_json::json!({
+                "policy": {
+                    "contact_request_deposit": "5120000000000000000000",
+                    "message_price_multiplier": 1,
+                    "max_message_length": 5,
+                    "rate_limit": null,
+                },
+            }))
+            .transact()
             .await
+            .unwrap()
+            .into_result()
             .unwrap();
-        let contract_bytes = utils::cargo::build_contract("../contract").await.unwrap();
+        bob.owner
+            .call(bob.contract.id(), "set_auto_reply")
+            .args_json(serde_json::json!({
+                "auto_reply": {
+                    "message": "Bob is away",
+                    "start": null,
+                    "end": null,
+                    "first_message_only": false,
+                },
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let view_budget = || async {
+            bob.owner
+                .view(bob.contract.id(), "view_auto_reply_budget")
+                .args(b"{}".to_vec())
+                .await
+                .unwrap()
+                .json::<String>()
+                .unwrap()
+                .parse::<u128>()
+                .unwrap()
+        };
+        let budget = view_budget().await;
+        let response = send_message(&alice, &bob, "Hi").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        assert_eq!(view_budget().await, budget);
+
+        // Turning the auto-reply off stops the replies
+        bob.owner
+            .call(bob.contract.id(), "set_auto_reply")
+            .args_json(serde_json::json!({ "auto_reply": null }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let auto_reply: Option<types::AutoReply> = bob
+            .owner
+            .view(bob.contract.id(), "view_auto_reply")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(auto_reply.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_edit_message() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let response = send_message(&alice, &bob, "Hello, Bob").await;
+        let message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "edit_message")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "message_id": message_id,
+                "new_content": "Hello, Bob!!!",
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        let event = parse_event(&response, 0);
+        assert_eq!(*event.as_message_edited().unwrap().message_id, message_id);
+        assert_eq!(
+            response.json::<types::EditMessageResponse>().unwrap(),
+            types::EditMessageResponse::Edited
+        );
+
+        // Bob sees the latest version, and can look up the previous one
+        let message: Option<types::Message> = bob
+            .owner
+            .view(bob.contract.id(), "view_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        let message = message.unwrap();
+        assert_eq!(message.content.as_text(), Some("Hello, Bob!!!"));
+        assert!(message.edited);
+        let revisions: Vec<types::MessageRevision> = bob
+            .owner
+            .view(bob.contract.id(), "view_message_revisions")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].content.as_text(), Some("Hello, Bob"));
+
+        // Alice's copy of the message is also updated
+        let messages: Vec<types::MessageWithId> = alice
+            .owner
+            .view(alice.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::ConversationCursor>>()
+            .unwrap()
+            .items;
+        assert_eq!(messages[0].message.content.as_text(), Some("Hello, Bob!!!"));
+        assert!(messages[0].message.edited);
+
+        // Only the latest revisions are kept
+        for edit in 0..MAX_MESSAGE_REVISIONS {
+            let response = alice
+                .owner
+                .call(alice.contract.id(), "edit_message")
+                .args_json(serde_json::json!({
+                    "account": bob.contract.id(),
+                    "message_id": message_id,
+                    "new_content": format!("Hi, Bob ({edit})"),
+                }))
+                .deposit(1_000_000_000_000_000_000_000_000)
+                .max_gas()
+                .transact()
+                .await
+                .unwrap();
+            assert_eq!(
+                response.json::<types::EditMessageResponse>().unwrap(),
+                types::EditMessageResponse::Edited
+            );
+        }
+        let revisions: Vec<types::MessageRevision> = bob
+            .owner
+            .view(bob.contract.id(), "view_message_revisions")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(revisions.len(), MAX_MESSAGE_REVISIONS);
+        assert_eq!(revisions[0].content.as_text(), Some("Hello, Bob!!!"));
 
+        // Only the sender can edit the message
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "ext_edit_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+                "new_content": "Hello, Eve!",
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::EditMessageResponse>().unwrap(),
+            types::EditMessageResponse::NotSender
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retract_message() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let mut message_ids = Vec::new();
+        for content in ["First", "Wrong chat!", "Last"] {
+            let response = send_message(&alice, &bob, content).await;
+            let message_id = parse_event(&response, 0)
+                .as_message_sent()
+                .unwrap()
+                .message_id
+                .clone()
+                .unwrap()
+                .into_owned();
+            message_ids.push(message_id);
+        }
+
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "retract_message")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "message_id": message_ids[1],
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        let event = parse_event(&response, 0);
+        assert_eq!(
+            *event.as_message_retracted().unwrap().message_id,
+            message_ids[1]
+        );
+        assert_eq!(
+            response.json::<types::RetractMessageResponse>().unwrap(),
+            types::RetractMessageResponse::Retracted
+        );
+
+        // The message is gone, but the rest of the thread is intact on both sides
+        let message: Option<types::Message> = bob
+            .owner
+            .view(bob.contract.id(), "view_message")
+            .args_json(serde_json::json!({
+                "message_id": message_ids[1],
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(message.is_none());
+        let tombstone: Option<types::Tombstone> = bob
+            .owner
+            .view(bob.contract.id(), "view_tombstone")
+            .args_json(serde_json::json!({
+                "message_id": message_ids[1],
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(tombstone.unwrap().parent_id, Some(message_ids[0]));
+
+        let unread: Vec<types::UnreadMessageView> = bob
+            .owner
+            .view(bob.contract.id(), "view_unread")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<types::UnreadMessageView, u64>>()
+            .unwrap()
+            .items;
+        assert_eq!(unread.len(), 2);
+
+        let messages: Vec<types::MessageWithId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items;
+        let contents: Vec<&str> = messages
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap())
+            .collect();
+        assert_eq!(contents, ["First", "Last"]);
+
+        let messages: Vec<types::MessageWithId> = alice
+            .owner
+            .view(alice.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::ConversationCursor>>()
+            .unwrap()
+            .items;
+        let ids: Vec<types::MessageId> = messages.into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, [message_ids[0], message_ids[2]]);
+
+        // Nothing else is kept about the retracted message
+        let status: Option<types::DeliveryStatus> = alice
+            .owner
+            .view(alice.contract.id(), "view_delivery_status")
+            .args_json(serde_json::json!({
+                "message_id": message_ids[1],
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(status.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_purge_messages() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let mut message_ids = Vec::new();
+        for content in ["First", "Second", "Third", "Last"] {
+            let response = send_message(&alice, &bob, content).await;
+            let message_id = parse_event(&response, 0)
+                .as_message_sent()
+                .unwrap()
+                .message_id
+                .clone()
+                .unwrap()
+                .into_owned();
+            message_ids.push(message_id);
+        }
+
+        // Only read messages are purged, and the thread skips over them. A long thread is
+        // gone through over several calls.
+        read_message(&bob, message_ids[1]).await;
+        let result = purge_read_messages(&bob, Some(&alice), Some(2), None).await;
+        assert_eq!(result.freed.0, 0);
+        assert!(result.next.is_some());
+        let result = purge_read_messages(&bob, Some(&alice), None, result.next).await;
+        assert!(result.freed.0 > 0);
+        assert!(result.next.is_none());
+        let message: Option<types::Message> = bob
+            .owner
+            .view(bob.contract.id(), "view_message")
+            .args_json(serde_json::json!({
+                "message_id": message_ids[1],
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(message.is_none());
+        let contents: Vec<String> = view_conversation(&bob, &alice)
+            .await
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap().to_string())
+            .collect();
+        assert_eq!(contents, ["First", "Third", "Last"]);
+
+        // A purged message, or one from another thread, is not a valid cursor
+        for (sender, before) in [(&alice, message_ids[1]), (&bob, message_ids[2])] {
+            let page: types::Page<types::MessageWithId, types::MessageId> = bob
+                .owner
+                .view(bob.contract.id(), "view_thread")
+                .args_json(serde_json::json!({
+                    "sender": sender.contract.id(),
+                    "before": before,
+                }))
+                .await
+                .unwrap()
+                .json()
+                .unwrap();
+            assert!(page.items.is_empty());
+            assert!(page.next.is_none());
+        }
+
+        // Without an account, the read messages of the whole inbox are purged
+        let charlie = setup_messenger_contract("charlie.test.near", &worker).await;
+        connect_contacts(&charlie, &bob).await;
+        let response = send_message(&charlie, &bob, "Hi, Bob").await;
+        let charlie_message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+        read_message(&bob, charlie_message_id).await;
+        read_message(&bob, message_ids[0]).await;
+        let freed = call_purge_method(
+            &bob,
+            "purge_read_messages",
+            serde_json::json!({ "before": u64::MAX.to_string(), "max_size": 1 }),
+        )
+        .await;
+        assert!(freed > 0);
+        assert!(view_conversation(&bob, &charlie).await.is_empty());
+        let contents: Vec<String> = view_conversation(&bob, &alice)
+            .await
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap().to_string())
+            .collect();
+        assert_eq!(contents, ["Third", "Last"]);
+
+        // Tombstones can be cleared as well
+        alice
+            .owner
+            .call(alice.contract.id(), "retract_message")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "message_id": message_ids[2],
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let freed = call_purge_method(
+            &bob,
+            "clear_tombstones",
+            serde_json::json!({ "account": alice.contract.id() }),
+        )
+        .await;
+        assert!(freed > 0);
+        let tombstone: Option<types::Tombstone> = bob
+            .owner
+            .view(bob.contract.id(), "view_tombstone")
+            .args_json(serde_json::json!({
+                "message_id": message_ids[2],
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(tombstone.is_none());
+        send_message(&alice, &bob, "Again").await;
+        let contents: Vec<String> = view_conversation(&bob, &alice)
+            .await
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap().to_string())
+            .collect();
+        assert_eq!(contents, ["Last", "Again"]);
+
+        // Deleting the thread removes both sides of the conversation, over several calls
+        send_message(&bob, &alice, "Hi, Alice!").await;
+        let freed = call_purge_method(
+            &bob,
+            "delete_thread",
+            serde_json::json!({ "account": alice.contract.id(), "max_size": 1 }),
+        )
+        .await;
+        assert!(freed > 0);
+        assert!(view_conversation(&bob, &alice).await.is_empty());
+
+        // Only the owner can delete messages
+        let response = alice
+            .owner
+            .call(bob.contract.id(), "delete_thread")
+            .args_json(serde_json::json!({ "account": alice.contract.id() }))
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+    }
+
+    async fn purge_read_messages(
+        this: &MessengerInstance,
+        account: Option<&MessengerInstance>,
+        max_size: Option<usize>,
+        from: Option<types::PurgeCursor>,
+    ) -> types::PurgeResult {
+        this.owner
+            .call(this.contract.id(), "purge_read_messages")
+            .args_json(serde_json::json!({
+                "account": account.map(|account| account.contract.id()),
+                "before": u64::MAX.to_string(),
+                "max_size": max_size,
+                "from": from,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+            .json()
+            .unwrap()
+    }
+
+    /// Call one of the methods which delete messages until it has gone through all of them,
+    /// returning the number of bytes freed.
+    async fn call_purge_method(
+        this: &MessengerInstance,
+        method: &str,
+        mut args: serde_json::Value,
+    ) -> u64 {
+        let mut freed = 0;
+        loop {
+            let result: types::PurgeResult = this
+                .owner
+                .call(this.contract.id(), method)
+                .args_json(&args)
+                .max_gas()
+                .transact()
+                .await
+                .unwrap()
+                .json()
+                .unwrap();
+            freed += result.freed.0;
+            match result.next {
+                Some(next) => args["from"] = serde_json::json!(next),
+                None => return freed,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_messages() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        // The message expires as soon as it is received
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "message": "Your code is 123456",
+                "ttl": "1",
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        let message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+        send_message(&alice, &bob, "Hello, Bob!").await;
+
+        // Expired messages are not shown on either side
+        for (this, other) in [(&bob, &alice), (&alice, &bob)] {
+            let contents: Vec<String> = view_conversation(this, other)
+                .await
+                .iter()
+                .map(|m| m.message.content.as_text().unwrap().to_string())
+                .collect();
+            assert_eq!(contents, ["Hello, Bob!"]);
+        }
+        let message: Option<types::Message> = bob
+            .owner
+            .view(bob.contract.id(), "view_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(message.is_none());
+
+        // The time-to-live counts from when the message was sent, on both sides
+        let mut expiries = Vec::new();
+        for this in [&alice, &bob] {
+            let expiry: Option<String> = this
+                .owner
+                .view(this.contract.id(), "view_message_expiry")
+                .args_json(serde_json::json!({
+                    "message_id": message_id,
+                }))
+                .await
+                .unwrap()
+                .json()
+                .unwrap();
+            expiries.push(expiry.unwrap());
+        }
+        assert_eq!(expiries[0], expiries[1]);
+
+        // Anyone can sweep expired messages. The storage the message used is refunded to the
+        // storage balance of Alice with her contract.
+        alice
+            .owner
+            .call(alice.contract.id(), "storage_deposit")
+            .args_json(serde_json::json!({
+                "registration_only": true,
+            }))
+            .deposit(MIN_STORAGE_BALANCE)
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let initial_total = view_storage_total(&alice).await;
+        let sweeper = worker.dev_create_account().await.unwrap();
+        let response = sweeper
+            .call(bob.contract.id(), "sweep_expired")
+            .args_json(serde_json::json!({ "max": 10 }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(response.json::<u32>().unwrap(), 1);
+        assert!(view_storage_total(&alice).await > initial_total);
+        let event = parse_event(&response, 0);
+        let event_details = event.as_message_expired().unwrap();
+        assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
+        assert_eq!(*event_details.message_id, message_id);
+        let tombstone: Option<types::Tombstone> = bob
+            .owner
+            .view(bob.contract.id(), "view_tombstone")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(tombstone.is_some());
+        let response = sweeper
+            .call(bob.contract.id(), "sweep_expired")
+            .args_json(serde_json::json!({ "max": 10 }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(response.json::<u32>().unwrap(), 0);
+
+        let response = sweeper
+            .call(alice.contract.id(), "sweep_expired")
+            .args_json(serde_json::json!({ "max": 10 }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(response.json::<u32>().unwrap(), 1);
+        assert_eq!(view_conversation(&alice, &bob).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_receipts() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let mut message_ids = Vec::new();
+        for content in ["Hello, Bob!", "Are you there?"] {
+            let response = send_message(&alice, &bob, content).await;
+            let message_id = parse_event(&response, 0)
+                .as_message_sent()
+                .unwrap()
+                .message_id
+                .clone()
+                .unwrap()
+                .into_owned();
+            message_ids.push(message_id);
+        }
+
+        // Bob reads the first message and Alice is told about it
+        let response = read_message(&bob, message_ids[0]).await;
+        let event = parse_event(&response, 0);
+        let event_details = event.as_message_read().unwrap();
+        assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
+        assert_eq!(event_details.receiver.as_str(), bob.contract.id().as_str());
+        assert!(view_read_receipt(&alice, message_ids[0]).await.is_some());
+
+        // Bob turns off read receipts for Alice before reading the second message
+        bob.owner
+            .call(bob.contract.id(), "set_read_receipts")
+            .args_json(serde_json::json!({
+                "enabled": false,
+                "account": alice.contract.id(),
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let response = read_message(&bob, message_ids[1]).await;
+        assert!(response.logs().is_empty());
+        assert!(view_read_receipt(&alice, message_ids[1]).await.is_none());
+    }
+
+    async fn read_message(
+        this: &MessengerInstance,
+        message_id: types::MessageId,
+    ) -> ExecutionFinalResult {
+        let response = this
+            .owner
+            .call(this.contract.id(), "read_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_success());
+        response
+    }
+
+    async fn view_read_receipt(
+        this: &MessengerInstance,
+        message_id: types::MessageId,
+    ) -> Option<serde_json::Value> {
+        this.owner
+            .view(this.contract.id(), "view_read_receipt")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_group_messages() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        let charlie = setup_messenger_contract("charlie.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+        connect_contacts(&alice, &charlie).await;
+
+        // Alice creates a group with Bob and Charlie
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "create_group")
+            .args_json(serde_json::json!({
+                "name": "Waterloo",
+                "members": [bob.contract.id(), charlie.contract.id()],
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        let group_id: types::GroupId = response.json().unwrap();
+        let event = parse_event(&response, 0);
+        assert_eq!(*event.as_group_created().unwrap().group_id, group_id);
+
+        for member in [&bob, &charlie] {
+            let group: Option<types::Group> = member
+                .owner
+                .view(member.contract.id(), "view_group")
+                .args_json(serde_json::json!({
+                    "group_id": group_id,
+                }))
+                .await
+                .unwrap()
+                .json()
+                .unwrap();
+            assert_eq!(group.unwrap().members.len(), 3);
+        }
+
+        // Bob can message Charlie through the group even though they are not contacts
+        let response = send_group_message(&bob, group_id, "Hello, group!").await;
+        // Bob learns the message was delivered to each of the other members
+        for index in [3, 4] {
+            let event = parse_event(&response, index);
+            let event_details = event.as_message_delivery().unwrap();
+            assert_eq!(event_details.sender.as_str(), bob.contract.id().as_str());
+            assert_eq!(*event_details.status, types::DeliveryStatus::Delivered);
+        }
+        let thread = view_group_thread(&charlie, group_id).await;
+        assert_eq!(thread.len(), 1);
+        assert_eq!(thread[0].message.content.as_text(), Some("Hello, group!"));
+        assert_eq!(
+            thread[0].message.sender.as_str(),
+            bob.contract.id().as_str()
+        );
+
+        // After Charlie is removed they no longer receive messages, but keep the history
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "remove_group_member")
+            .args_json(serde_json::json!({
+                "group_id": group_id,
+                "account": charlie.contract.id(),
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_success());
+        let event = parse_event(&response, 0);
+        assert_eq!(
+            event.as_group_member().unwrap().member.as_str(),
+            charlie.contract.id().as_str()
+        );
+
+        send_group_message(&bob, group_id, "Charlie left").await;
+        assert_eq!(view_group_thread(&charlie, group_id).await.len(), 1);
+        let thread = view_group_thread(&alice, group_id).await;
+        let contents: Vec<&str> = thread
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap())
+            .collect();
+        assert_eq!(contents, ["Hello, group!", "Charlie left"]);
+        let thread = view_group_thread(&bob, group_id).await;
+        assert_eq!(thread.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_storage_management() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        // Without a deposit or a storage balance the message can not be sent
+        let response = send_message_without_deposit(&alice, &bob, "Hello").await;
+        assert!(response.is_failure());
+
+        // Registering takes the minimum balance, which pays for the registration itself
+        let bounds: types::StorageBalanceBounds = alice
+            .owner
+            .view(alice.contract.id(), "storage_balance_bounds")
+            .args_json(serde_json::json!({}))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(bounds.min.0, MIN_STORAGE_BALANCE);
+        let response = bob
+            .owner
+            .call(alice.contract.id(), "storage_deposit")
+            .args(b"{}".to_vec())
+            .deposit(MIN_STORAGE_BALANCE - 1)
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+        bob.owner
+            .call(alice.contract.id(), "storage_deposit")
+            .args_json(serde_json::json!({
+                "registration_only": true,
+            }))
+            .deposit(2 * MIN_STORAGE_BALANCE)
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let balance: Option<types::StorageBalance> = bob
+            .owner
+            .view(alice.contract.id(), "storage_balance_of")
+            .args_json(serde_json::json!({
+                "account_id": bob.owner.id(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        let balance = balance.unwrap();
+        assert_eq!(balance.total.0, MIN_STORAGE_BALANCE);
+        assert_eq!(balance.available.0, 0);
+
+        // Alice pre-funds a storage balance which pays for her messages. She is already
+        // registered, since the refunds from connecting with Bob went to her balance.
+        let initial_balance = view_storage_balance(&alice).await;
+        let deposit: u128 = 1_000_000_000_000_000_000_000_000;
+        alice
+            .owner
+            .call(alice.contract.id(), "storage_deposit")
+            .args(b"{}".to_vec())
+            .deposit(deposit)
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let balance = view_storage_balance(&alice).await;
+        assert_eq!(balance, initial_balance + deposit);
+
+        // Alice only pays for the storage the message uses in Bob's contract
+        let storage_usage = view_storage_usage(&bob).await;
+        let response = send_message_without_deposit(&alice, &bob, "Hello").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let used = view_storage_usage(&bob).await - storage_usage;
+        let remaining_balance = view_storage_balance(&alice).await;
+        assert_eq!(remaining_balance, balance - used * STORAGE_PRICE_PER_BYTE);
+
+        // With an attached deposit, the part Bob's contract does not use goes to the balance
+        let storage_usage = view_storage_usage(&bob).await;
+        let message = "Hello again";
+        let response = send_message(&alice, &bob, message).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let used = view_storage_usage(&bob).await - storage_usage;
+        let forwarded = (MESSAGE_STORAGE_OVERHEAD + message.len() as u128) * STORAGE_PRICE_PER_BYTE;
+        assert_eq!(
+            view_storage_balance(&alice).await,
+            remaining_balance + forwarded - used * STORAGE_PRICE_PER_BYTE
+        );
+
+        // The rest of the balance can be withdrawn
+        alice
+            .owner
+            .call(alice.contract.id(), "storage_withdraw")
+            .args(b"{}".to_vec())
+            .deposit(1)
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        assert_eq!(view_storage_balance(&alice).await, 0);
+    }
+
+    async fn send_message_without_deposit(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        message: &str,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message": message,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    /// Bytes of storage used by the contract account.
+    async fn view_storage_usage(instance: &MessengerInstance) -> u128 {
+        instance
+            .contract
+            .view_account()
+            .await
+            .unwrap()
+            .storage_usage as u128
+    }
+
+    async fn view_storage_balance(instance: &MessengerInstance) -> u128 {
+        let balance: serde_json::Value = instance
+            .owner
+            .view(instance.contract.id(), "storage_balance_of")
+            .args_json(serde_json::json!({
+                "account_id": instance.owner.id(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        balance["available"].as_str().unwrap().parse().unwrap()
+    }
+
+    /// The total storage balance of the owner, or 0 if the owner is not registered.
+    async fn view_storage_total(instance: &MessengerInstance) -> u128 {
+        let balance: serde_json::Value = instance
+            .owner
+            .view(instance.contract.id(), "storage_balance_of")
+            .args_json(serde_json::json!({
+                "account_id": instance.owner.id(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        balance["total"]
+            .as_str()
+            .map(|total| total.parse().unwrap())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_messages() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        // Bob publishes an encryption key
+        let secret_key = encryption::generate_secret_key();
+        let public_key = types::EncryptionKey {
+            id: 0,
+            public_key: secret_key.public_key().to_bytes().to_vec().into(),
+        };
+        let key_id: types::EncryptionKeyId = bob
+            .owner
+            .call(bob.contract.id(), "set_encryption_key")
+            .args_json(serde_json::json!({
+                "public_key": public_key.public_key,
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(key_id, 0);
+
+        // Alice encrypts a message with Bob's current key
+        let bob_key: Option<types::EncryptionKey> = alice
+            .owner
+            .view(bob.contract.id(), "view_encryption_key")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        let bob_key = bob_key.unwrap();
+        assert_eq!(bob_key, public_key);
+        let payload = encryption::encrypt(&bob_key, b"Hello, Bob!").unwrap();
+        let response = send_encrypted_message(&alice, &bob, payload.clone()).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+
+        // Only the ciphertext is stored, which Bob can decrypt
+        let message: Option<types::Message> = bob
+            .owner
+            .view(bob.contract.id(), "view_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        let content = message.unwrap().content;
+        assert_eq!(content, types::MessageBody::Encrypted(payload.clone()));
+        let types::MessageBody::Encrypted(received_payload) = content else {
+            panic!("Expected an encrypted message");
+        };
+        assert_eq!(
+            encryption::decrypt(&secret_key, &received_payload).unwrap(),
+            b"Hello, Bob!"
+        );
+
+        // Messages for a key Bob never published are rejected
+        let payload = types::EncryptedPayload {
+            key_id: 7,
+            ..payload
+        };
+        let response = send_encrypted_message(&alice, &bob, payload).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::UnknownEncryptionKey
+        );
+    }
+
+    async fn send_encrypted_message(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        payload: types::EncryptedPayload,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message": types::MessageBody::Encrypted(payload),
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_message_kinds() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let attachment = serde_json::json!({
+            "type": "attachment",
+            "content_hash": "4ykbMSWt7kR1XbZXwCLASHb6G1mb1xyTuizjWRXXWg4b",
+            "mime_type": "image/png",
+            "size": "1024",
+            "location": "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+        });
+        let contents = [
+            (serde_json::json!("Hello, Bob!"), types::MessageKind::Text),
+            (
+                serde_json::json!({"type": "markdown", "text": "**Hello**, Bob!"}),
+                types::MessageKind::Markdown,
+            ),
+            (
+                serde_json::json!({"type": "link", "url": "https://near.org", "title": "Near"}),
+                types::MessageKind::Link,
+            ),
+            (attachment, types::MessageKind::Attachment),
+        ];
+        for (content, kind) in contents {
+            let response = alice
+                .owner
+                .call(alice.contract.id(), "send_message")
+                .args_json(serde_json::json!({
+                    "account": bob.contract.id(),
+                    "message": content,
+                }))
+                .deposit(1_000_000_000_000_000_000_000_000)
+                .max_gas()
+                .transact()
+                .await
+                .unwrap();
+            assert_eq!(
+                response.json::<types::MessageResponse>().unwrap(),
+                types::MessageResponse::Received
+            );
+
+            // Both events tell what kind of content the message has
+            let event = parse_event(&response, 0);
+            let event_details = event.as_message_sent().unwrap();
+            assert_eq!(event_details.kind, Some(kind));
+            let message_id = event_details.message_id.clone().unwrap().into_owned();
+            let event = parse_event(&response, 1);
+            assert_eq!(event.as_message_received().unwrap().kind, Some(kind));
+
+            // The content is viewed in the same format it was sent in
+            let message: serde_json::Value = bob
+                .owner
+                .view(bob.contract.id(), "view_message")
+                .args_json(serde_json::json!({
+                    "message_id": message_id,
+                }))
+                .await
+                .unwrap()
+                .json()
+                .unwrap();
+            assert_eq!(message["content"], content);
+            let message: types::Message = serde_json::from_value(message).unwrap();
+            assert_eq!(message.content.kind(), kind);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reactions() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+
+        let response = send_message(&alice, &bob, "Hello, Bob!").await;
+        let message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+
+        // Bob reacts to the message he received, and Alice to the message she sent
+        let response = react(&bob, &alice, message_id, "👍").await;
+        assert_eq!(
+            response.json::<types::ReactResponse>().unwrap(),
+            types::ReactResponse::Reacted
+        );
+        let event = parse_event(&response, 0);
+        let event_details = event.as_message_reaction().unwrap();
+        assert_eq!(event_details.sender.as_str(), bob.contract.id().as_str());
+        assert_eq!(
+            event_details.receiver.as_str(),
+            alice.contract.id().as_str()
+        );
+        assert_eq!(event_details.reaction, "👍");
+        let response = react(&alice, &bob, message_id, "🎉").await;
+        assert_eq!(
+            response.json::<types::ReactResponse>().unwrap(),
+            types::ReactResponse::Reacted
+        );
+
+        // Both sides of the conversation see both reactions
+        let message: Option<types::Message> = bob
+            .owner
+            .view(bob.contract.id(), "view_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        let reactions: Vec<String> = message
+            .unwrap()
+            .reactions
+            .into_iter()
+            .map(|r| r.reaction)
+            .collect();
+        assert_eq!(reactions, ["👍", "🎉"]);
+        let conversation: Vec<types::MessageWithId> = alice
+            .owner
+            .view(alice.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<String, types::ConversationCursor>>()
+            .unwrap()
+            .items;
+        assert_eq!(conversation[0].message.reactions.len(), 2);
+
+        // The same reaction can not be added twice, and only a few can be added
+        let response = react(&bob, &alice, message_id, "👍").await;
+        assert!(response.is_failure());
+        for reaction in ["😀", "😂"] {
+            let response = react(&bob, &alice, message_id, reaction).await;
+            assert_eq!(
+                response.json::<types::ReactResponse>().unwrap(),
+                types::ReactResponse::Reacted
+            );
+        }
+        let response = react(&bob, &alice, message_id, "😢").await;
+        assert!(response.is_failure());
+    }
+
+    #[tokio::test]
+    async fn test_replies() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        let charlie = setup_messenger_contract("charlie.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+        connect_contacts(&alice, &charlie).await;
+
+        let response = send_message(&alice, &bob, "Lunch?").await;
+        let question_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+        send_message(&alice, &bob, "Or coffee?").await;
+
+        // Bob replies to the first message, even though it is not the latest one
+        let response = send_reply(&bob, &alice, "Lunch!", question_id).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let reply_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+
+        // Both sides see the reply
+        for (this, sender) in [(&alice, &bob), (&bob, &bob)] {
+            let replies: Vec<types::MessageWithId> = this
+                .owner
+                .view(this.contract.id(), "view_replies")
+                .args_json(serde_json::json!({
+                    "message_id": question_id,
+                }))
+                .await
+                .unwrap()
+                .json::<types::Page<types::MessageWithId, u64>>()
+                .unwrap()
+                .items;
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0].id, reply_id);
+            assert_eq!(replies[0].message.reply_to, Some(question_id));
+            assert_eq!(
+                replies[0].message.sender.as_str(),
+                sender.contract.id().as_str()
+            );
+        }
+
+        // Messages from other conversations can not be replied to
+        let response = send_reply(&alice, &charlie, "Lunch?", question_id).await;
+        assert!(response.is_failure());
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        // Start from the first release, whose state has no version
+        let baseline_bytes = build_baseline_contract().await;
+        let alice = deploy_messenger_contract("alice.test.near", &worker, &baseline_bytes).await;
+        let bob = deploy_messenger_contract("bob.test.near", &worker, &baseline_bytes).await;
+
+        connect_contacts(&alice, &bob).await;
+        send_message(&alice, &bob, "Before the upgrade").await;
+        send_message(&alice, &bob, "Still before").await;
+
+        // Deploy the current code and migrate the state
+        let contract_bytes = utils::cargo::build_contract("../contract").await.unwrap();
+        for instance in [&alice, &bob] {
+            instance
+                .contract
+                .as_account()
+                .deploy(&contract_bytes)
+                .await
+                .unwrap()
+                .into_result()
+                .unwrap();
+        }
+        let version: u32 = bob
+            .contract
+            .view("view_state_version")
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(version, 0);
+        // Only the contract itself can migrate its state
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "migrate")
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+        for instance in [&alice, &bob] {
+            instance
+                .contract
+                .call("migrate")
+                .transact()
+                .await
+                .unwrap()
+                .into_result()
+                .unwrap();
+        }
+
+        let version: u32 = bob
+            .contract
+            .view("view_state_version")
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(version, 1);
+
+        // The messages are still there, as text
+        let messages: Vec<types::MessageWithId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items;
+        let contents: Vec<&str> = messages
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap())
+            .collect();
+        assert_eq!(contents, vec!["Before the upgrade", "Still before"]);
+        let unread = bob
+            .owner
+            .view(bob.contract.id(), "view_unread")
+            .await
+            .unwrap()
+            .json::<types::Page<types::UnreadMessageView, u64>>()
+            .unwrap()
+            .items;
+        assert_eq!(unread.len(), 2);
+
+        // Existing contacts are listed once the owner backfills them
+        assert!(view_contacts(&bob).await.is_empty());
+        let metadata: Option<types::ContactMetadata> = bob
+            .owner
+            .view(bob.contract.id(), "view_contact_metadata")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(metadata, Some(types::ContactMetadata::default()));
+        let added: u32 = bob
+            .owner
+            .call(bob.contract.id(), "backfill_contacts")
+            .args_json(serde_json::json!({
+                "accounts": [alice.contract.id(), bob.owner.id()],
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(added, 1);
+        let contacts = view_contacts(&bob).await;
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].account.as_str(), alice.contract.id().as_str());
+
+        // And they are still contacts
+        let response = send_message(&alice, &bob, "After the upgrade").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let response = send_message(&bob, &alice, "Still here").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let messages = view_conversation(&bob, &alice).await;
+        let contents: Vec<&str> = messages
+            .iter()
+            .map(|m| m.message.content.as_text().unwrap())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "Before the upgrade",
+                "Still before",
+                "After the upgrade",
+                "Still here"
+            ]
+        );
+
+        // Messages received before the upgrade can be deleted like the others
+        let freed = call_purge_method(
+            &bob,
+            "delete_thread",
+            serde_json::json!({ "account": alice.contract.id() }),
+        )
+        .await;
+        assert!(freed > 0);
+        assert!(view_conversation(&bob, &alice).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+        send_message(&alice, &bob, "Before the upgrade").await;
+
+        let contract_bytes = utils::cargo::build_contract("../contract").await.unwrap();
+        // Only the owner can upgrade the contract
+        let response = upgrade(&alice.owner, &bob, contract_bytes.clone()).await;
+        assert!(response.is_failure());
+
+        // Invalid code is rejected and the contract keeps working
+        let response = upgrade(&bob.owner, &bob, b"not wasm".to_vec()).await;
+        assert!(response.is_failure());
+        let response = send_message(&alice, &bob, "Still there?").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+
+        upgrade(&bob.owner, &bob, contract_bytes)
+            .await
+            .into_result()
+            .unwrap();
+
+        let version: u32 = bob
+            .contract
+            .view("view_state_version")
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(version, 1);
+        let messages: Vec<types::MessageWithId> = bob
+            .owner
+            .view(bob.contract.id(), "view_thread")
+            .args_json(serde_json::json!({
+                "sender": alice.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items;
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delegated_access() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        let bot = worker.dev_create_account().await.unwrap();
+
+        connect_contacts(&alice, &bob).await;
+        let response = send_message(&alice, &bob, "Hello, Bob!").await;
+        let message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+
+        // Only the owner can grant permissions
+        let response =
+            grant_permissions(&bot, &bob, bot.id(), serde_json::json!(["ReadOnly"]), None).await;
+        assert!(response.is_failure());
+        let response = grant_permissions(
+            &bob.owner,
+            &bob,
+            bot.id(),
+            serde_json::json!(["ReadOnly"]),
+            None,
+        )
+        .await;
+        assert!(response.is_success());
+        let delegate: Option<types::Delegate> = bob
+            .owner
+            .view(bob.contract.id(), "view_delegate")
+            .args_json(serde_json::json!({
+                "account": bot.id(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(
+            delegate.unwrap().permissions,
+            vec![types::Permission::ReadOnly]
+        );
+
+        // The bot can mark messages as read, but not send messages
+        let response = bot
+            .call(bob.contract.id(), "read_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_success());
+        let response = bot
+            .call(bob.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+                "message": "Hi, Alice!",
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+
+        // Expired permissions can not be used
+        let response = grant_permissions(
+            &bob.owner,
+            &bob,
+            bot.id(),
+            serde_json::json!(["ReadOnly"]),
+            Some("1".into()),
+        )
+        .await;
+        assert!(response.is_success());
+        let response = bot
+            .call(bob.contract.id(), "read_message")
+            .args_json(serde_json::json!({
+                "message_id": message_id,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+
+        // Revoked permissions are removed
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "revoke_permissions")
+            .args_json(serde_json::json!({
+                "account": bot.id(),
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_success());
+        let delegates: Vec<types::DelegateWithAccount> = bob
+            .owner
+            .view(bob.contract.id(), "view_delegates")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<types::DelegateWithAccount, u64>>()
+            .unwrap()
+            .items;
+        assert!(delegates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ownership() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        let team = worker.dev_create_account().await.unwrap();
+        let bot = worker.dev_create_account().await.unwrap();
+
+        let response = grant_permissions(
+            &bob.owner,
+            &bob,
+            bot.id(),
+            serde_json::json!(["ReadOnly"]),
+            None,
+        )
+        .await;
+        assert!(response.is_success());
+
+        // Only the owner can propose a new owner
+        let response = team
+            .call(bob.contract.id(), "propose_owner")
+            .args_json(serde_json::json!({
+                "new_owner": team.id(),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+
+        // A proposal can be cancelled before it is accepted
+        bob.owner
+            .call(bob.contract.id(), "propose_owner")
+            .args_json(serde_json::json!({
+                "new_owner": bot.id(),
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        bob.owner
+            .call(bob.contract.id(), "cancel_owner_proposal")
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let response = bot
+            .call(bob.contract.id(), "accept_ownership")
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "propose_owner")
+            .args_json(serde_json::json!({
+                "new_owner": team.id(),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        let event = parse_event(&response, 0);
+        let event_details = event.as_ownership().unwrap();
+        assert_eq!(
+            event_details.previous_owner.as_str(),
+            bob.owner.id().as_str()
+        );
+        assert_eq!(event_details.new_owner.as_str(), team.id().as_str());
+
+        // The owner does not change until the proposed owner accepts
+        let owner: AccountId = bob
+            .contract
+            .view("view_owner")
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(&owner, bob.owner.id());
+        let response = bot
+            .call(bob.contract.id(), "accept_ownership")
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+        let response = team
+            .call(bob.contract.id(), "accept_ownership")
+            .transact()
+            .await
+            .unwrap();
+        let event = parse_event(&response, 0);
+        let event_details = event.as_ownership().unwrap();
+        assert_eq!(
+            event_details.previous_owner.as_str(),
+            bob.owner.id().as_str()
+        );
+        assert_eq!(event_details.new_owner.as_str(), team.id().as_str());
+
+        let owner: AccountId = bob
+            .contract
+            .view("view_owner")
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(&owner, team.id());
+        let proposed_owner: Option<AccountId> = bob
+            .contract
+            .view("view_proposed_owner")
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(proposed_owner.is_none());
+
+        // Only the new owner can use owner-only methods, and delegates of the previous owner
+        // lose their permissions
+        for (caller, expect_success) in [(&bob.owner, false), (&team, true)] {
+            let response = caller
+                .call(bob.contract.id(), "set_read_receipts")
+                .args_json(serde_json::json!({
+                    "enabled": false,
+                }))
+                .transact()
+                .await
+                .unwrap();
+            assert_eq!(response.is_success(), expect_success);
+        }
+        let delegate: Option<types::Delegate> = bob
+            .contract
+            .view("view_delegate")
+            .args_json(serde_json::json!({
+                "account": bot.id(),
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(delegate.is_none());
+    }
+
+    async fn grant_permissions(
+        caller: &workspaces::Account,
+        instance: &MessengerInstance,
+        account: &AccountId,
+        permissions: serde_json::Value,
+        expires_at: Option<String>,
+    ) -> ExecutionFinalResult {
+        caller
+            .call(instance.contract.id(), "grant_permissions")
+            .args_json(serde_json::json!({
+                "account": account,
+                "permissions": permissions,
+                "expires_at": expires_at,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn upgrade(
+        caller: &workspaces::Account,
+        instance: &MessengerInstance,
+        code: Vec<u8>,
+    ) -> ExecutionFinalResult {
+        caller
+            .call(instance.contract.id(), "upgrade")
+            .args(code)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn send_reply(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        message: &str,
+        reply_to: types::MessageId,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message": message,
+                "reply_to": reply_to,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn react(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        message_id: types::MessageId,
+        reaction: &str,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "react")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message_id": message_id,
+                "reaction": reaction,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn send_group_message(
+        from: &MessengerInstance,
+        group_id: types::GroupId,
+        message: &str,
+    ) -> ExecutionFinalResult {
+        let response = from
+            .owner
+            .call(from.contract.id(), "send_group_message")
+            .args_json(serde_json::json!({
+                "group_id": group_id,
+                "message": message,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_success());
+        response
+    }
+
+    async fn view_group_thread(
+        this: &MessengerInstance,
+        group_id: types::GroupId,
+    ) -> Vec<types::MessageWithId> {
+        this.owner
+            .view(this.contract.id(), "view_group_thread")
+            .args_json(serde_json::json!({
+                "group_id": group_id,
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::MessageId>>()
+            .unwrap()
+            .items
+    }
+
+    async fn add_contact(from: &MessengerInstance, to: &MessengerInstance) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "add_contact")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_remove_contact() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+        send_message(&alice, &bob, "Hello, Bob!").await;
+
+        // Alice removes Bob, keeping the history
+        let response = remove_contact(&alice, &bob, None).await;
+        assert_eq!(
+            response.json::<types::RemoveContactResponse>().unwrap(),
+            types::RemoveContactResponse::Removed
+        );
+        for (index, this, contact) in [(0, &alice, &bob), (1, &bob, &alice)] {
+            let event = parse_event(&response, index);
+            let event_details = event.as_contact_removed().unwrap();
+            assert_eq!(event_details.this.as_str(), this.contract.id().as_str());
+            assert_eq!(
+                event_details.contact.as_str(),
+                contact.contract.id().as_str()
+            );
+        }
+
+        // Neither side can send messages anymore, but both keep the history
+        for (from, to) in [(&alice, &bob), (&bob, &alice)] {
+            let response = send_message(from, to, "Are you there?").await;
+            assert!(response.is_failure());
+            assert_eq!(view_conversation(from, to).await.len(), 1);
+        }
+
+        // Once contacts again, Bob removes Alice and purges the history on his side only
+        connect_contacts(&alice, &bob).await;
+        let response = remove_contact(&bob, &alice, Some(true)).await;
+        assert_eq!(
+            response.json::<types::RemoveContactResponse>().unwrap(),
+            types::RemoveContactResponse::Removed
+        );
+        assert!(view_conversation(&bob, &alice).await.is_empty());
+        assert_eq!(view_conversation(&alice, &bob).await.len(), 1);
+
+        // Only contacts can be removed
+        let response = remove_contact(&bob, &alice, None).await;
+        assert!(response.is_failure());
+    }
+
+    #[tokio::test]
+    async fn test_decline_and_cancel_contact_request() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        // Bob declines Alice's request
+        add_contact(&alice, &bob).await;
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "decline_contact")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::DeclineContactResponse>().unwrap(),
+            types::DeclineContactResponse::Declined
+        );
+        for index in [0, 1] {
+            let event = parse_event(&response, index);
+            let event_details = event.as_contact_request_declined().unwrap();
+            assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
+            assert_eq!(event_details.receiver.as_str(), bob.contract.id().as_str());
+        }
+        assert!(view_pending_contacts(&bob).await.is_empty());
+
+        // Alice can send a new request, then cancel it
+        let response = add_contact(&alice, &bob).await;
+        assert_eq!(
+            response.json::<types::AddContactResponse>().unwrap(),
+            types::AddContactResponse::Pending
+        );
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "cancel_contact_request")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::CancelContactResponse>().unwrap(),
+            types::CancelContactResponse::Cancelled
+        );
+        for index in [0, 1] {
+            let event = parse_event(&response, index);
+            let event_details = event.as_contact_request_cancelled().unwrap();
+            assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
+            assert_eq!(event_details.receiver.as_str(), bob.contract.id().as_str());
+        }
+        assert!(view_pending_contacts(&bob).await.is_empty());
+
+        // The cancelled request can no longer be accepted
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "accept_contact")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::AcceptContactResponse>().unwrap(),
+            types::AcceptContactResponse::UnknownAccount
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_requests() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        let charlie = setup_messenger_contract("charlie.test.near", &worker).await;
+
+        // Message requests are not accepted until the owner sets a policy
+        let fee = "100000000000000000000000"; // 0.1 Near
+        let response = send_message_request(&alice, &bob, "Hi, I'm Alice", fee).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::NotConnected
+        );
+        bob.owner
+            .call(bob.contract.id(), "set_message_request_policy")
+            .args_json(serde_json::json!({
+                "policy": {
+                    "fee": fee,
+                    "refund": "OnAccept",
+                },
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+
+        // Without the fee the request is rejected
+        let response = send_message_request(&alice, &bob, "Hi, I'm Alice", "0").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::InsufficientDeposit
+        );
+
+        // Alice and Charlie each send a message request
+        let response = send_message_request(&alice, &bob, "Hi, I'm Alice", fee).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        let event = parse_event(&response, 2);
+        let event_details = event.as_message_request_received().unwrap();
+        assert_eq!(event_details.sender.as_str(), alice.contract.id().as_str());
+        assert_eq!(event_details.receiver.as_str(), bob.contract.id().as_str());
+        let response = send_message_request(&charlie, &bob, "Hi, I'm Charlie", fee).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+
+        // Only one request can be sent
+        let response = send_message_request(&alice, &bob, "Hello?", fee).await;
+        assert!(response.is_failure());
+
+        let requests = view_message_requests(&bob).await;
+        assert_eq!(requests.len(), 2);
+        assert!(view_conversation(&bob, &alice).await.is_empty());
+
+        // Bob accepts Alice's request, so her message moves to his inbox and the fee is
+        // refunded to Alice's storage balance with her contract
+        let initial_total = view_storage_total(&alice).await;
+        let response = bob
+            .owner
+            .call(bob.contract.id(), "accept_contact")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::AcceptContactResponse>().unwrap(),
+            types::AcceptContactResponse::Accepted
+        );
+        let messages = view_conversation(&bob, &alice).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message.content.as_text(), Some("Hi, I'm Alice"));
+        assert_eq!(
+            view_storage_total(&alice).await,
+            initial_total + fee.parse::<u128>().unwrap()
+        );
+        let response = send_message(&bob, &alice, "Hi, Alice!").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+
+        // Bob blocks Charlie, which drops his request
+        bob.owner
+            .call(bob.contract.id(), "block_account")
+            .args_json(serde_json::json!({
+                "account": charlie.contract.id(),
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        assert!(view_message_requests(&bob).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spam_policy() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+        connect_contacts(&alice, &bob).await;
+
+        let policy = serde_json::json!({
+            "policy": {
+                "contact_request_deposit": "5120000000000000000000",
+                "message_price_multiplier": 1,
+                "max_message_length": 10,
+                "rate_limit": {
+                    "max_messages": 2,
+                    "window": "3600000000000", // 1 hour
+                },
+            },
+        });
+
+        // Only the owner can set the policy
+        let response = alice
+            .owner
+            .call(bob.contract.id(), "set_spam_policy")
+            .args_json(&policy)
+            .transact()
+            .await
+            .unwrap();
+        assert!(response.is_failure());
+        bob.owner
+            .call(bob.contract.id(), "set_spam_policy")
+            .args_json(&policy)
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+
+        let response = send_message(&alice, &bob, "This message is too long").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::TooLarge
+        );
+
+        // Paying for a message request does not lift the limit
+        let charlie = setup_messenger_contract("charlie.test.near", &worker).await;
+        let fee = "100000000000000000000000"; // 0.1 Near
+        bob.owner
+            .call(bob.contract.id(), "set_message_request_policy")
+            .args_json(serde_json::json!({
+                "policy": {
+                    "fee": fee,
+                    "refund": "OnAccept",
+                },
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let response = send_message_request(&charlie, &bob, "This message is too long", fee).await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::TooLarge
+        );
+        assert!(view_message_requests(&bob).await.is_empty());
+
+        // Rejected messages do not count towards the rate limit
+        for _ in 0..2 {
+            let response = send_message(&alice, &bob, "Hi, Bob").await;
+            assert_eq!(
+                response.json::<types::MessageResponse>().unwrap(),
+                types::MessageResponse::Received
+            );
+        }
+        let response = send_message(&alice, &bob, "Hi, Bob").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::RateLimited
+        );
+        assert_eq!(view_conversation(&bob, &alice).await.len(), 2);
+
+        // Edits count towards the rate limit too, after the length limit
+        let message_id = view_conversation(&bob, &alice).await[0].id;
+        let response = edit_message(&alice, &bob, message_id, "This edit is too long", None).await;
+        assert_eq!(
+            response.json::<types::EditMessageResponse>().unwrap(),
+            types::EditMessageResponse::TooLarge
+        );
+        let response = edit_message(&alice, &bob, message_id, "Hi, Bob!", None).await;
+        assert_eq!(
+            response.json::<types::EditMessageResponse>().unwrap(),
+            types::EditMessageResponse::RateLimited
+        );
+
+        // With a price multiplier, the sender must forward the deposit the recipient asks for
+        bob.owner
+            .call(bob.contract.id(), "set_spam_policy")
+            .args_json(serde_json::json!({
+                "policy": {
+                    "contact_request_deposit": "5120000000000000000000",
+                    "message_price_multiplier": 3,
+                    "max_message_length": null,
+                    "rate_limit": null,
+                },
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        // Without a rate limit, the window kept for Alice can be deleted
+        let pruned: u32 = bob
+            .owner
+            .call(bob.contract.id(), "prune_message_windows")
+            .args_json(serde_json::json!({
+                "accounts": [alice.contract.id()],
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(pruned, 1);
+
+        let response = send_message(&alice, &bob, "Hi again").await;
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::InsufficientDeposit
+        );
+        let deposit: String = alice
+            .owner
+            .view(bob.contract.id(), "view_message_deposit")
+            .args_json(serde_json::json!({
+                "message": "Hi again",
+            }))
+            .await
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(
+            deposit.parse::<u128>().unwrap(),
+            3 * (MESSAGE_STORAGE_OVERHEAD + 8) * STORAGE_PRICE_PER_BYTE
+        );
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": bob.contract.id(),
+                "message": "Hi again",
+                "deposit": &deposit,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::MessageResponse>().unwrap(),
+            types::MessageResponse::Received
+        );
+        assert_eq!(view_conversation(&bob, &alice).await.len(), 3);
+        let message_id = parse_event(&response, 0)
+            .as_message_sent()
+            .unwrap()
+            .message_id
+            .clone()
+            .unwrap()
+            .into_owned();
+
+        // The same goes for edits
+        let response = edit_message(&alice, &bob, message_id, "Hi again", None).await;
+        assert_eq!(
+            response.json::<types::EditMessageResponse>().unwrap(),
+            types::EditMessageResponse::InsufficientDeposit
+        );
+        let response = edit_message(&alice, &bob, message_id, "Hi again", Some(&deposit)).await;
+        assert_eq!(
+            response.json::<types::EditMessageResponse>().unwrap(),
+            types::EditMessageResponse::Edited
+        );
+
+        // The same goes for group messages, where the deposit is given for each member
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "create_group")
+            .args_json(serde_json::json!({
+                "name": "Waterloo",
+                "members": [bob.contract.id()],
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        let group_id: types::GroupId = response.json().unwrap();
+        let response = send_group_message(&alice, group_id, "Hi again").await;
+        let event = parse_event(&response, 1);
+        assert_eq!(
+            *event.as_message_delivery().unwrap().status,
+            types::DeliveryStatus::Rejected {
+                reason: types::MessageResponse::InsufficientDeposit
+            }
+        );
+        let response = alice
+            .owner
+            .call(alice.contract.id(), "send_group_message")
+            .args_json(serde_json::json!({
+                "group_id": group_id,
+                "message": "Hi again",
+                "deposits": {
+                    bob.contract.id().as_str(): deposit,
+                },
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        let event = parse_event(&response, 2);
+        assert_eq!(
+            *event.as_message_delivery().unwrap().status,
+            types::DeliveryStatus::Delivered
+        );
+        assert_eq!(view_group_thread(&bob, group_id).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_contact_metadata() {
+        let worker = workspaces::sandbox().await.unwrap();
+
+        let alice = setup_messenger_contract("alice.test.near", &worker).await;
+        let bob = setup_messenger_contract("bob.test.near", &worker).await;
+
+        connect_contacts(&alice, &bob).await;
+        let contacts = view_contacts(&bob).await;
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].account.as_str(), alice.contract.id().as_str());
+        assert_eq!(contacts[0].metadata, types::ContactMetadata::default());
+
+        bob.owner
+            .call(bob.contract.id(), "set_contact_metadata")
+            .args_json(serde_json::json!({
+                "account": alice.contract.id(),
+                "metadata": {
+                    "nickname": "Ali",
+                    "notes": "Met at the hackathon",
+                    "muted": true,
+                    "archived": true,
+                },
+            }))
+            .transact()
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let contacts = view_contacts(&bob).await;
+        assert_eq!(contacts[0].metadata.nickname.as_deref(), Some("Ali"));
+        assert!(contacts[0].metadata.muted);
+        assert!(contacts[0].metadata.archived);
+
+        // Messages from a muted contact do not count as unread
+        send_message(&alice, &bob, "Hi, Bob").await;
+        let unread: Vec<types::UnreadMessageView> = bob
+            .owner
+            .view(bob.contract.id(), "view_unread")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<types::UnreadMessageView, u64>>()
+            .unwrap()
+            .items;
+        assert!(unread.is_empty());
+        assert_eq!(view_conversation(&bob, &alice).await.len(), 1);
+
+        // The metadata is dropped along with the contact
+        remove_contact(&bob, &alice, None).await;
+        assert!(view_contacts(&bob).await.is_empty());
+    }
+
+    async fn view_contacts(this: &MessengerInstance) -> Vec<types::ContactWithMetadata> {
+        this.owner
+            .view(this.contract.id(), "view_contacts")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<types::ContactWithMetadata, u64>>()
+            .unwrap()
+            .items
+    }
+
+    async fn send_message_request(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        message: &str,
+        fee: &str,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "send_message_request")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message": message,
+                "fee": fee,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn view_message_requests(this: &MessengerInstance) -> Vec<types::MessageRequest> {
+        this.owner
+            .view(this.contract.id(), "view_message_requests")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageRequest, u64>>()
+            .unwrap()
+            .items
+    }
+
+    async fn view_pending_contacts(this: &MessengerInstance) -> Vec<AccountId> {
+        this.owner
+            .view(this.contract.id(), "view_pending_contacts")
+            .args(b"{}".to_vec())
+            .await
+            .unwrap()
+            .json::<types::Page<AccountId, u64>>()
+            .unwrap()
+            .items
+    }
+
+    async fn remove_contact(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        purge_history: Option<bool>,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "remove_contact")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "purge_history": purge_history,
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn view_conversation(
+        this: &MessengerInstance,
+        account: &MessengerInstance,
+    ) -> Vec<types::MessageWithId> {
+        this.owner
+            .view(this.contract.id(), "view_conversation")
+            .args_json(serde_json::json!({
+                "account": account.contract.id(),
+            }))
+            .await
+            .unwrap()
+            .json::<types::Page<types::MessageWithId, types::ConversationCursor>>()
+            .unwrap()
+            .items
+    }
+
+    /// Makes `a` and `b` contacts of one another.
+    async fn connect_contacts(a: &MessengerInstance, b: &MessengerInstance) {
+        let response = add_contact(a, b).await;
+        assert_eq!(
+            response.json::<types::AddContactResponse>().unwrap(),
+            types::AddContactResponse::Pending
+        );
+        let response = b
+            .owner
+            .call(b.contract.id(), "accept_contact")
+            .args_json(serde_json::json!({
+                "account": a.contract.id(),
+            }))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.json::<types::AcceptContactResponse>().unwrap(),
+            types::AcceptContactResponse::Accepted
+        );
+    }
+
+    async fn send_message(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        message: &str,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "send_message")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message": message,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn edit_message(
+        from: &MessengerInstance,
+        to: &MessengerInstance,
+        message_id: types::MessageId,
+        new_content: &str,
+        deposit: Option<&str>,
+    ) -> ExecutionFinalResult {
+        from.owner
+            .call(from.contract.id(), "edit_message")
+            .args_json(serde_json::json!({
+                "account": to.contract.id(),
+                "message_id": message_id,
+                "new_content": new_content,
+                "deposit": deposit,
+            }))
+            .deposit(1_000_000_000_000_000_000_000_000)
+            .max_gas()
+            .transact()
+            .await
+            .unwrap()
+    }
+
+    async fn setup_messenger_contract(
+        account_name: &str,
+        worker: &workspaces::Worker<workspaces::network::Sandbox>,
+    ) -> MessengerInstance {
+        // This is needed because of a quirk of how `cargo-near` works. It doesn't handle
+        // cargo workspaces properly yet.
+        tokio::fs::create_dir_all("../target/near/near_messenger")
+            .await
+            .unwrap();
+        let contract_bytes = utils::cargo::build_contract("../contract").await.unwrap();
+        deploy_messenger_contract(account_name, worker, &contract_bytes).await
+    }
+
+    /// Build the contract as it was in its first release, which is kept as a fixture.
+    async fn build_baseline_contract() -> Vec<u8> {
+        tokio::fs::create_dir_all("fixtures/baseline-contract/target/near/near_messenger")
+            .await
+            .unwrap();
+        utils::cargo::build_contract("fixtures/baseline-contract")
+            .await
+            .unwrap()
+    }
+
+    async fn deploy_messenger_contract(
+        account_name: &str,
+        worker: &workspaces::Worker<workspaces::network::Sandbox>,
+        contract_bytes: &[u8],
+    ) -> MessengerInstance {
         let (_, sk) = worker.dev_generate().await;
         let account = worker
             .create_tla(account_name.parse().unwrap(), sk)
@@ -173,7 +3098,7 @@ mod tests {
             .into_result()
             .unwrap();
         let contract = messenger_account
-            .deploy(&contract_bytes)
+            .deploy(contract_bytes)
             .await
             .unwrap()
             .into_result()